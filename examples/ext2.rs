@@ -23,7 +23,7 @@ use std::{
 };
 
 use blown_fuse::{
-    io::{Attrs, Entry, EntryType, FsInfo, Gid, Ino, Known, Mode, Stat, Ttl, Uid},
+    io::{Attrs, Entry, EntryType, FsInfo, Gid, Ino, Known, RawMode, Stat, Ttl, Uid},
     mount::{mount_sync, Options},
     ops,
     session::{Dispatch, Start},
@@ -49,6 +49,7 @@ struct Ext2 {
 struct Resolved {
     ino: Ino,
     inode: &'static Inode,
+    inode_type: EntryType,
 }
 
 #[derive(Pod, Zeroable, Copy, Clone)]
@@ -159,30 +160,15 @@ impl Ext2 {
                 let ino = Ino(header.inode as u64);
                 let name = OsStr::from_bytes(&bytes[..header.name_len as usize]);
 
-                let inode = Resolved {
-                    ino,
-                    inode: self.inode(ino)?,
-                };
-
-                let entry = Entry {
-                    inode,
-                    name,
-                    offset: position,
-                    ttl: Ttl::MAX,
-                };
+                let inode = self.resolve(ino)?;
+                let entry = Entry::new(name, inode).offset(position);
 
                 break Ok(Some((entry, position)));
             }
         })
     }
 
-    fn inode(&self, ino: Ino) -> Result<&'static Inode, Errno> {
-        let Ino(ino) = match ino {
-            Ino::ROOT => EXT2_ROOT,
-            EXT2_ROOT => Ino::ROOT,
-            ino => ino,
-        };
-
+    fn inode(&self, Ino(ino): Ino) -> Result<&'static Inode, Errno> {
         if ino == 0 {
             log::error!("Attempted to access the null (0) inode");
             return Err(Errno::EIO);
@@ -204,6 +190,25 @@ impl Ext2 {
         Ok(from_bytes(&self.block(block)?[start..end]))
     }
 
+    // Inodes of unknown type are corrupt, replying with any type would mislead the kernel
+    fn resolve(&self, ino: Ino) -> Result<Resolved, Errno> {
+        let inode = self.inode(ino)?;
+        let mode = RawMode(inode.i_mode.into());
+
+        match mode.file_type() {
+            Some(inode_type) => Ok(Resolved {
+                ino,
+                inode,
+                inode_type,
+            }),
+
+            None => {
+                log::error!("Inode {} has invalid mode {:o}", ino, mode.0);
+                Err(Errno::EIO)
+            }
+        }
+    }
+
     fn seek_contiguous(
         &self,
         inode: &'static Inode,
@@ -291,6 +296,10 @@ impl Ext2 {
     fn block_size(&self) -> usize {
         1024usize << self.superblock.s_log_block_size
     }
+
+    fn fragment_size(&self) -> usize {
+        1024usize << self.superblock.s_log_frag_size
+    }
 }
 
 impl Ext2 {
@@ -312,14 +321,16 @@ impl Ext2 {
         log::info!("UUID: {}", Uuid::from_bytes(self.superblock.s_uuid));
         log::info!("Label: {}", label.escape_debug());
 
+        let (reply, root) = reply.and_then(self.resolve(EXT2_ROOT))?;
+
         log::info!("Mounted successfully");
-        reply.ok()
+        reply.root(&root)
     }
 
     async fn statfs<'o>(&self, (_, reply): Op<'o, ops::Statfs>) -> Done<'o> {
         let total_blocks = self.superblock.s_blocks_count as u64;
         let free_blocks = self.superblock.s_free_blocks_count as u64;
-        let available_blocks = free_blocks - self.superblock.s_r_blocks_count as u64;
+        let available_blocks = free_blocks.saturating_sub(self.superblock.s_r_blocks_count as u64);
         let total_inodes = self.superblock.s_inodes_count as u64;
         let free_inodes = self.superblock.s_free_inodes_count as u64;
 
@@ -330,6 +341,7 @@ impl Ext2 {
                 free_blocks,
                 available_blocks,
             )
+            .fragment_size(self.fragment_size() as u32)
             .inodes(total_inodes, free_inodes)
             .max_filename(255);
 
@@ -337,10 +349,8 @@ impl Ext2 {
     }
 
     async fn getattr<'o>(&self, (request, reply): Op<'o, ops::Getattr>) -> Done<'o> {
-        let ino = request.ino();
-        let (reply, inode) = reply.and_then(self.inode(ino))?;
-
-        reply.stat(&Resolved { ino, inode })
+        let (reply, inode) = reply.and_then(self.resolve(request.ino()))?;
+        reply.stat(&inode)
     }
 
     async fn lookup<'o>(&self, (request, reply): Op<'o, ops::Lookup>) -> Done<'o> {
@@ -370,14 +380,12 @@ impl Ext2 {
     }
 
     async fn readlink<'o>(&self, (request, reply): Op<'o, ops::Readlink>) -> Done<'o> {
-        let ino = request.ino();
-        let (mut reply, inode) = reply.and_then(self.inode(ino))?;
-
-        let resolved = Resolved { ino, inode };
-        if resolved.inode_type() != EntryType::Symlink {
+        let (mut reply, resolved) = reply.and_then(self.resolve(request.ino()))?;
+        if resolved.inode_type != EntryType::Symlink {
             return reply.invalid_argument();
         }
 
+        let inode = resolved.inode;
         let size = inode.i_size as usize;
         if size < size_of::<[u32; 15]>() {
             return reply.slice(&cast_slice(&inode.i_block)[..size]);
@@ -426,21 +434,7 @@ impl Stat for Resolved {
     }
 
     fn inode_type(&self) -> EntryType {
-        let inode_type = self.inode.i_mode >> 12;
-        match inode_type {
-            0x01 => EntryType::Fifo,
-            0x02 => EntryType::CharacterDevice,
-            0x04 => EntryType::Directory,
-            0x06 => EntryType::BlockDevice,
-            0x08 => EntryType::File,
-            0x0A => EntryType::Symlink,
-            0x0C => EntryType::Socket,
-
-            _ => {
-                log::error!("Inode {} has invalid type {:x}", self.ino, inode_type);
-                EntryType::File
-            }
-        }
+        self.inode_type
     }
 
     fn attrs(&self) -> (Attrs, Ttl) {
@@ -458,12 +452,27 @@ impl Stat for Resolved {
                 Uid::from_raw(inode.i_uid.into()),
                 Gid::from_raw(inode.i_gid.into()),
             )
-            .mode(Mode::from_bits_truncate(inode.i_mode.into()))
+            .mode(RawMode(inode.i_mode.into()).permissions())
             .blocks(inode.i_blocks.into())
             .block_size(512)
             .times(access, modify, change)
             .links(inode.i_links_count.into());
 
+        // Device numbers are stored in i_block, either old-style in the first entry or
+        // new-style (as the kernel's new_encode_dev()) in the second one
+        let attrs = match self.inode_type() {
+            inode_type @ (EntryType::CharacterDevice | EntryType::BlockDevice) => {
+                let device = match inode.i_block {
+                    [0, new, ..] => new,
+                    [old, ..] => old,
+                };
+
+                attrs.special(inode_type, device)
+            }
+
+            _ => attrs,
+        };
+
         (attrs, Ttl::MAX)
     }
 }
@@ -0,0 +1,604 @@
+// A writable filesystem that lives entirely in memory, like tmpfs(5).
+//
+// Inodes are destroyed once they are both unlinked and forgotten by the kernel. Renames that
+// exchange both entries or leave a whiteout behind fail with EINVAL.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    ops::ControlFlow,
+    path::Path,
+    time::SystemTime,
+};
+
+use tokio::runtime::Runtime;
+
+use blown_fuse::{
+    dispatch,
+    io::{
+        Attrs, CursorTable, DirHandle, Entry, EntryType, Gid, HandleTable, Ino, Interruptible,
+        Known, Mode, RenameFlags, SetTime, Stat, Timestamp, Ttl, Uid,
+    },
+    mount::mount_sync,
+    ops,
+    session::Start,
+    Done, Errno, FuseResult, Op,
+};
+
+use clap::{App, Arg};
+
+// Growing a file past this fails with EFBIG instead of attempting a huge allocation
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+struct Memfs {
+    inodes: HashMap<Ino, Inode>,
+    next_ino: u64,
+    open_dirs: HandleTable<(), DirHandle>,
+    dir_cursors: CursorTable<OsString>,
+}
+
+struct Inode {
+    ino: Ino,
+    contents: Contents,
+    mode: Mode,
+    uid: Uid,
+    gid: Gid,
+    links: u32,
+    lookups: u64,
+    access: Timestamp,
+    modify: Timestamp,
+    change: Timestamp,
+}
+
+enum Contents {
+    Directory(BTreeMap<OsString, Ino>),
+    File(Vec<u8>),
+    Symlink(OsString),
+}
+
+// Counts the kernel's references to an inode once the reply has been sent
+struct Counted<'a>(&'a mut Inode);
+
+impl Memfs {
+    fn inode(&self, ino: Ino) -> Result<&Inode, Errno> {
+        self.inodes.get(&ino).ok_or(Errno::ENOENT)
+    }
+
+    fn inode_mut(&mut self, ino: Ino) -> Result<&mut Inode, Errno> {
+        self.inodes.get_mut(&ino).ok_or(Errno::ENOENT)
+    }
+
+    fn children(&self, ino: Ino) -> Result<&BTreeMap<OsString, Ino>, Errno> {
+        match &self.inode(ino)?.contents {
+            Contents::Directory(children) => Ok(children),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn children_mut(&mut self, ino: Ino) -> Result<&mut BTreeMap<OsString, Ino>, Errno> {
+        let parent = self.inode_mut(ino)?;
+        parent.touch();
+
+        match &mut parent.contents {
+            Contents::Directory(children) => Ok(children),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn file_mut(&mut self, ino: Ino) -> Result<&mut Vec<u8>, Errno> {
+        self.inode_mut(ino)?.data_mut()
+    }
+
+    fn make_node(
+        &mut self,
+        parent: Ino,
+        name: &OsStr,
+        contents: Contents,
+        mode: Mode,
+        (uid, gid): (Uid, Gid),
+    ) -> Result<Counted<'_>, Errno> {
+        let ino = Ino(self.next_ino);
+        match self.children_mut(parent)?.entry(name.to_owned()) {
+            std::collections::btree_map::Entry::Occupied(_) => return Err(Errno::EEXIST),
+            std::collections::btree_map::Entry::Vacant(entry) => entry.insert(ino),
+        };
+
+        self.next_ino += 1;
+        if let Contents::Directory(_) = contents {
+            self.inode_mut(parent)?.links += 1;
+        }
+
+        let inode = self
+            .inodes
+            .entry(ino)
+            .or_insert_with(|| Inode::new(ino, contents, mode, (uid, gid)));
+
+        Ok(Counted(inode))
+    }
+
+    fn remove_node(&mut self, parent: Ino, name: &OsStr, is_dir: bool) -> Result<(), Errno> {
+        let ino = *self.children(parent)?.get(name).ok_or(Errno::ENOENT)?;
+
+        match (&self.inode(ino)?.contents, is_dir) {
+            (Contents::Directory(children), true) if !children.is_empty() => {
+                return Err(Errno::ENOTEMPTY)
+            }
+
+            (Contents::Directory(_), true) => self.inode_mut(parent)?.links -= 1,
+            (Contents::Directory(_), false) => return Err(Errno::EISDIR),
+            (_, true) => return Err(Errno::ENOTDIR),
+            (_, false) => (),
+        }
+
+        self.children_mut(parent)?.remove(name);
+
+        let inode = self.inode_mut(ino)?;
+        inode.links = if is_dir { 0 } else { inode.links - 1 };
+        inode.change = SystemTime::now().into();
+
+        self.collect(ino);
+        Ok(())
+    }
+
+    fn move_node(
+        &mut self,
+        (old_dir, old_name): (Ino, &OsStr),
+        (new_dir, new_name): (Ino, &OsStr),
+        flags: RenameFlags,
+    ) -> Result<(), Errno> {
+        if flags.intersects(RenameFlags::EXCHANGE | RenameFlags::WHITEOUT) {
+            return Err(Errno::EINVAL);
+        }
+
+        let ino = *self.children(old_dir)?.get(old_name).ok_or(Errno::ENOENT)?;
+        let is_dir = matches!(self.inode(ino)?.contents, Contents::Directory(_));
+
+        match self.children(new_dir)?.get(new_name).copied() {
+            Some(_) if flags.contains(RenameFlags::NOREPLACE) => return Err(Errno::EEXIST),
+            Some(target) if target == ino => return Ok(()),
+            Some(_) => self.remove_node(new_dir, new_name, is_dir)?,
+            None => (),
+        }
+
+        self.children_mut(old_dir)?.remove(old_name);
+        self.children_mut(new_dir)?.insert(new_name.to_owned(), ino);
+
+        if is_dir && old_dir != new_dir {
+            self.inode_mut(old_dir)?.links -= 1;
+            self.inode_mut(new_dir)?.links += 1;
+        }
+
+        self.inode_mut(ino)?.change = SystemTime::now().into();
+        Ok(())
+    }
+
+    // Inodes must remain alive while the kernel still knows about them
+    fn collect(&mut self, ino: Ino) {
+        if let Some(inode) = self.inodes.get(&ino) {
+            if inode.links == 0 && inode.lookups == 0 {
+                self.inodes.remove(&ino);
+            }
+        }
+    }
+
+    fn lookup<'o>(&mut self, (request, reply): Op<'o, ops::Lookup>) -> Done<'o> {
+        let (reply, children) = reply.and_then(self.children(request.ino()))?;
+
+        match children.get(request.name()).copied() {
+            Some(ino) => {
+                let (reply, inode) = reply.and_then(self.inode_mut(ino))?;
+                reply.known(Counted(inode), Ttl::MAX)
+            }
+
+            None => reply.not_found(),
+        }
+    }
+
+    fn forget<'o>(&mut self, (request, reply): Op<'o, ops::Forget>) -> Done<'o> {
+        for (ino, count) in request.forget_list() {
+            if let Some(inode) = self.inodes.get_mut(&ino) {
+                inode.lookups = inode.lookups.saturating_sub(count);
+                self.collect(ino);
+            }
+        }
+
+        reply.ok()
+    }
+
+    fn getattr<'o>(&mut self, (request, reply): Op<'o, ops::Getattr>) -> Done<'o> {
+        let (reply, inode) = reply.and_then(self.inode(request.ino()))?;
+        reply.stat(inode)
+    }
+
+    fn setattr<'o>(&mut self, (request, reply): Op<'o, ops::Setattr>) -> Done<'o> {
+        let now = Timestamp::now();
+        let (mut reply, inode) = reply.and_then(self.inode_mut(request.ino()))?;
+
+        if let Some(size) = request.new_size() {
+            let (next_reply, size) = reply.and_then(file_len(size))?;
+            let (next_reply, data) = next_reply.and_then(inode.data_mut())?;
+            reply = next_reply;
+
+            data.resize(size, 0);
+
+            inode.modify = now;
+        }
+
+        if let Some(mode) = request.new_mode() {
+            inode.mode = mode;
+        }
+
+        let (uid, gid) = request.new_owner();
+        inode.uid = uid.unwrap_or(inode.uid);
+        inode.gid = gid.unwrap_or(inode.gid);
+
+        let time = |time| match time {
+            SetTime::Now => now,
+            SetTime::At(time) => time,
+        };
+
+        inode.access = request.new_atime().map_or(inode.access, time);
+        inode.modify = request.new_mtime().map_or(inode.modify, time);
+        inode.change = request.new_ctime().unwrap_or(now);
+
+        reply.stat(inode)
+    }
+
+    fn readlink<'o>(&mut self, (request, reply): Op<'o, ops::Readlink>) -> Done<'o> {
+        let (reply, inode) = reply.and_then(self.inode(request.ino()))?;
+
+        match &inode.contents {
+            Contents::Symlink(target) => reply.blob(target),
+            _ => reply.invalid_argument(),
+        }
+    }
+
+    fn symlink<'o>(&mut self, (request, reply): Op<'o, ops::Symlink>) -> Done<'o> {
+        let target = Contents::Symlink(request.target().to_owned());
+        let owner = (request.uid(), request.gid());
+        let mode = Mode::from_bits_truncate(0o777);
+
+        let result = self.make_node(request.ino(), request.name(), target, mode, owner);
+        let (reply, inode) = reply.and_then(result)?;
+
+        reply.known(inode, Ttl::MAX)
+    }
+
+    fn mkdir<'o>(&mut self, (request, reply): Op<'o, ops::Mkdir>) -> Done<'o> {
+        let contents = Contents::Directory(Default::default());
+        let owner = (request.uid(), request.gid());
+
+        let result = self.make_node(
+            request.ino(),
+            request.name(),
+            contents,
+            request.mode(),
+            owner,
+        );
+        let (reply, inode) = reply.and_then(result)?;
+
+        reply.known(inode, Ttl::MAX)
+    }
+
+    fn unlink<'o>(&mut self, (request, reply): Op<'o, ops::Unlink>) -> Done<'o> {
+        let result = self.remove_node(request.ino(), request.name(), false);
+        let (reply, ()) = reply.and_then(result)?;
+
+        reply.ok()
+    }
+
+    fn rmdir<'o>(&mut self, (request, reply): Op<'o, ops::Rmdir>) -> Done<'o> {
+        let result = self.remove_node(request.ino(), request.name(), true);
+        let (reply, ()) = reply.and_then(result)?;
+
+        reply.ok()
+    }
+
+    fn link<'o>(&mut self, (request, reply): Op<'o, ops::Link>) -> Done<'o> {
+        let source = request.source_ino();
+        let (reply, inode) = reply.and_then(self.inode(source))?;
+        if let Contents::Directory(_) = inode.contents {
+            return reply.not_permitted();
+        }
+
+        let (reply, children) = reply.and_then(self.children_mut(request.ino()))?;
+        if children.contains_key(request.name()) {
+            return reply.fail(Errno::EEXIST);
+        }
+
+        children.insert(request.name().to_owned(), source);
+
+        let (reply, inode) = reply.and_then(self.inode_mut(source))?;
+        inode.links += 1;
+        inode.change = SystemTime::now().into();
+
+        reply.known(Counted(inode), Ttl::MAX)
+    }
+
+    fn rename<'o>(&mut self, (request, reply): Op<'o, ops::Rename>) -> Done<'o> {
+        let result = self.move_node(
+            (request.ino(), request.name()),
+            (request.new_dir(), request.new_name()),
+            request.flags(),
+        );
+
+        let (reply, ()) = reply.and_then(result)?;
+        reply.ok()
+    }
+
+    fn create<'o>(&mut self, (request, reply): Op<'o, ops::Create>) -> Done<'o> {
+        let contents = Contents::File(Vec::new());
+        let owner = (request.uid(), request.gid());
+
+        let result = self.make_node(
+            request.ino(),
+            request.name(),
+            contents,
+            request.mode(),
+            owner,
+        );
+        let (reply, inode) = reply.and_then(result)?;
+
+        reply.known(inode, Ttl::MAX)
+    }
+
+    fn open<'o>(&mut self, (request, reply): Op<'o, ops::Open>) -> Done<'o> {
+        let (reply, data) = reply.and_then(self.file_mut(request.ino()))?;
+        if request.truncate() {
+            data.clear();
+        }
+
+        reply.ok()
+    }
+
+    fn read<'o>(&mut self, (request, reply): Op<'o, ops::Read>) -> Done<'o> {
+        let (reply, range) = reply.and_then(request.range().ok_or(Errno::EINVAL))?;
+        let (reply, data) = reply.and_then(self.file_mut(request.ino()))?;
+
+        let end = data.len().min(range.end.try_into().unwrap_or(usize::MAX));
+        let start = end.min(range.start.try_into().unwrap_or(usize::MAX));
+
+        reply.slice(&data[start..end])
+    }
+
+    fn write<'o>(&mut self, (request, reply): Op<'o, ops::Write>) -> Done<'o> {
+        let (reply, range) = reply.and_then(request.range().ok_or(Errno::EFBIG))?;
+        let (reply, end) = reply.and_then(file_len(range.end))?;
+        let start = end - request.data().len();
+
+        let (reply, inode) = reply.and_then(self.inode_mut(request.ino()))?;
+        let (reply, data) = reply.and_then(inode.data_mut())?;
+
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+
+        data[start..end].copy_from_slice(request.data());
+        if let Some(now) = request.modified() {
+            (inode.modify, inode.change) = (now, now);
+        }
+
+        reply.all()
+    }
+
+    fn release<'o>(&mut self, (_request, reply): Op<'o, ops::Release>) -> Done<'o> {
+        reply.ok()
+    }
+
+    fn opendir<'o>(&mut self, (request, reply): Op<'o, ops::Opendir>) -> Done<'o> {
+        let (reply, _) = reply.and_then(self.children(request.ino()))?;
+        reply.ok_with_handle(self.open_dirs.insert(()))
+    }
+
+    // Offsets are positions in the listing. A stashed cursor is the name at that position,
+    // which stays valid even if entries are added or removed in between
+    fn readdir<'o>(&mut self, (request, reply): Op<'o, ops::Readdir>) -> Done<'o> {
+        let (handle, offset) = (request.handle(), request.offset());
+        let cursor = self.dir_cursors.take(handle, offset);
+
+        let (reply, children) = reply.and_then(self.children(request.ino()))?;
+        let owned =
+            |((name, ino), position): ((&OsString, &Ino), u64)| (name.clone(), *ino, position);
+        let children: Vec<_> = match cursor {
+            Some(cursor) => children.range(cursor..).zip(offset..).map(owned).collect(),
+            None => children
+                .iter()
+                .zip(0..)
+                .skip(offset as usize)
+                .map(owned)
+                .collect(),
+        };
+
+        let mut reply = reply.buffered(Vec::new());
+        for (index, (name, ino, position)) in children.iter().enumerate() {
+            if !reply.would_fit(name.len()) {
+                self.dir_cursors.stash(handle, *position, name.clone());
+                break;
+            }
+
+            let (next_reply, inode) = reply.and_then(self.inode_mut(*ino))?;
+            let entry = Entry::new(name, Counted(inode)).offset(position + 1);
+
+            reply = match next_reply.entry(entry) {
+                Interruptible::Completed(reply, ()) => reply,
+                Interruptible::Interrupted(done) => {
+                    if let Some((name, _, position)) = children.get(index + 1) {
+                        self.dir_cursors.stash(handle, *position, name.clone());
+                    }
+
+                    return done;
+                }
+            };
+        }
+
+        reply.end()
+    }
+
+    fn releasedir<'o>(&mut self, (request, reply): Op<'o, ops::Releasedir>) -> Done<'o> {
+        self.open_dirs.remove(request.handle());
+        self.dir_cursors.release(request.handle());
+
+        reply.ok()
+    }
+}
+
+impl Inode {
+    fn new(ino: Ino, contents: Contents, mode: Mode, (uid, gid): (Uid, Gid)) -> Self {
+        let now = SystemTime::now().into();
+        let links = match contents {
+            Contents::Directory(_) => 2,
+            _ => 1,
+        };
+
+        Inode {
+            ino,
+            contents,
+            mode,
+            uid,
+            gid,
+            links,
+            lookups: 0,
+            access: now,
+            modify: now,
+            change: now,
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = SystemTime::now().into();
+        self.modify = now;
+        self.change = now;
+    }
+
+    fn data_mut(&mut self) -> Result<&mut Vec<u8>, Errno> {
+        match &mut self.contents {
+            Contents::File(data) => Ok(data),
+            Contents::Directory(_) => Err(Errno::EISDIR),
+            Contents::Symlink(_) => Err(Errno::EINVAL),
+        }
+    }
+}
+
+fn file_len(size: u64) -> Result<usize, Errno> {
+    if size > MAX_FILE_SIZE {
+        return Err(Errno::EFBIG);
+    }
+
+    usize::try_from(size).map_err(|_| Errno::EFBIG)
+}
+
+impl Stat for Inode {
+    fn ino(&self) -> Ino {
+        self.ino
+    }
+
+    fn inode_type(&self) -> EntryType {
+        match self.contents {
+            Contents::Directory(_) => EntryType::Directory,
+            Contents::File(_) => EntryType::File,
+            Contents::Symlink(_) => EntryType::Symlink,
+        }
+    }
+
+    fn attrs(&self) -> (Attrs, Ttl) {
+        let size = match &self.contents {
+            Contents::Directory(children) => children.len(),
+            Contents::File(data) => data.len(),
+            Contents::Symlink(target) => target.len(),
+        } as u64;
+
+        let attrs = Attrs::default()
+            .size(size)
+            .owner(self.uid, self.gid)
+            .mode(self.mode)
+            .blocks_from_size()
+            .times(self.access, self.modify, self.change)
+            .links(self.links);
+
+        (attrs, Ttl::MAX)
+    }
+}
+
+impl Known for Counted<'_> {
+    type Inode = Inode;
+
+    fn inode(&self) -> &Self::Inode {
+        self.0
+    }
+
+    fn unveil(self) {
+        self.0.lookups += 1;
+    }
+}
+
+async fn main_loop(session: Start, mut fs: Memfs) -> FuseResult<()> {
+    let session = session.start(|(_request, reply)| reply.ok()).await?;
+    let mut endpoint = session.endpoint();
+
+    loop {
+        let result = endpoint.receive(|dispatch| async {
+            dispatch!(dispatch, fs, {
+                Lookup => lookup,
+                Forget => forget,
+                Getattr => getattr,
+                Setattr => setattr,
+                Readlink => readlink,
+                Symlink => symlink,
+                Mkdir => mkdir,
+                Unlink => unlink,
+                Rmdir => rmdir,
+                Link => link,
+                Rename => rename,
+                Create => create,
+                Open => open,
+                Read => read,
+                Write => write,
+                Release => release,
+                Opendir => opendir,
+                Readdir => readdir,
+                Releasedir => releasedir,
+            })
+        });
+
+        match result.await? {
+            ControlFlow::Break(()) => break Ok(()),
+            ControlFlow::Continue(()) => continue,
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("memfs")
+        .about("in-memory FUSE filesystem")
+        .arg(Arg::from_usage("<mountpoint> 'Filesystem mountpoint'"))
+        .get_matches();
+
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .init();
+
+    let mountpoint = Path::new(matches.value_of("mountpoint").unwrap());
+    let session = mount_sync(mountpoint, &Default::default())?;
+
+    let fs = {
+        let owner = (nix::unistd::getuid(), nix::unistd::getgid());
+        let contents = Contents::Directory(Default::default());
+        let root = Inode::new(Ino::ROOT, contents, Mode::from_bits_truncate(0o755), owner);
+
+        Memfs {
+            inodes: [(Ino::ROOT, root)].into_iter().collect(),
+            next_ino: Ino::ROOT.as_raw() + 1,
+            open_dirs: Default::default(),
+            dir_cursors: Default::default(),
+        }
+    };
+
+    let result = Runtime::new()?.block_on(async move {
+        tokio::select! {
+            result = main_loop(session, fs) => result,
+            _ = tokio::signal::ctrl_c() => Ok(()),
+        }
+    });
+
+    Ok(result?)
+}
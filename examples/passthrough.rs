@@ -4,27 +4,30 @@
 // long requests.
 
 use std::{
-    collections::HashMap,
     fs::Metadata,
     ops::ControlFlow,
-    os::unix::fs::{FileTypeExt, MetadataExt},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
     path::{Path, PathBuf},
 };
 
 use tokio::{
     fs::{self, DirEntry, File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncSeekExt, AsyncWriteExt},
     runtime::Runtime,
 };
 
 use blown_fuse::{
+    dispatch,
     io::{
-        Attrs, Entry, EntryType, FsyncFlags, Gid, Ino, Known, Mode, OpenFlags, Stat, Timestamp,
-        Ttl, Uid,
+        Attrs, DirHandle, Entry, EntryType, FileHandle, FsyncFlags, Gid, HandleTable, Ino, Known,
+        LookupTable, Mode, OpenFlags, Stat, Timestamp, Ttl, Uid, Unveil,
     },
     mount::mount_sync,
     ops,
-    session::{Dispatch, Start},
+    session::Start,
     Done, Errno, FuseResult, Op,
 };
 
@@ -32,44 +35,15 @@ use clap::{App, Arg};
 use nix::unistd::mkdir;
 
 struct Passthrough {
-    known: HashMap<Ino, Inode>,
+    known: LookupTable<Inode>,
     root_dev: u64,
-    open_dirs: OpenMap<OpenDir>,
-    open_files: OpenMap<OpenFile>,
+    open_dirs: HandleTable<OpenDir, DirHandle>,
+    open_files: HandleTable<OpenFile, FileHandle>,
 }
 
 struct Inode {
     path: PathBuf,
     metadata: Metadata,
-    lookup_count: u64,
-}
-
-struct OpenMap<T> {
-    next: u64,
-    entries: HashMap<u64, T>,
-}
-
-impl<T> OpenMap<T> {
-    fn get(&mut self, handle: u64) -> Result<&mut T, Errno> {
-        self.entries.get_mut(&handle).ok_or(Errno::EINVAL)
-    }
-
-    fn insert(&mut self, entry: T) -> u64 {
-        let handle = self.next;
-        self.entries.insert(handle, entry);
-
-        self.next += 1;
-        handle
-    }
-}
-
-impl<T> Default for OpenMap<T> {
-    fn default() -> Self {
-        OpenMap {
-            next: 0,
-            entries: Default::default(),
-        }
-    }
 }
 
 struct OpenFile {
@@ -86,6 +60,15 @@ impl OpenFile {
 
         Ok(())
     }
+
+    // The file position moves along with writes, which must be kept track of for seek()
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.seek(offset).await?;
+        self.handle.write_all(data).await?;
+        self.offset = offset + data.len() as u64;
+
+        Ok(())
+    }
 }
 
 struct OpenDir {
@@ -93,11 +76,9 @@ struct OpenDir {
     children: Vec<DirEntry>,
 }
 
-struct New<'a>(&'a mut HashMap<Ino, Inode>, Inode);
-
 impl Passthrough {
     fn known(&self, ino: Ino) -> Result<&Inode, Errno> {
-        self.known.get(&ino).ok_or(Errno::ENOANO)
+        self.known.get(ino).ok_or(Errno::ENOANO)
     }
 
     async fn lookup<'o>(&mut self, (request, reply): Op<'o, ops::Lookup>) -> Done<'o> {
@@ -106,23 +87,14 @@ impl Passthrough {
         let path = parent.path.join(request.name());
         let (reply, metadata) = reply.and_then(fs::symlink_metadata(&path).await)?;
 
-        reply.known(New(&mut self.known, Inode::new(path, metadata)), Ttl::MAX)
+        reply.known(
+            unveil(&mut self.known, Inode::new(path, metadata)),
+            Ttl::MAX,
+        )
     }
 
     fn forget<'o>(&mut self, (request, reply): Op<'o, ops::Forget>) -> Done<'o> {
-        use std::collections::hash_map::Entry;
-
-        for (ino, count) in request.forget_list() {
-            if let Entry::Occupied(mut entry) = self.known.entry(ino) {
-                let inode = entry.get_mut();
-                inode.lookup_count = inode.lookup_count.saturating_sub(count);
-
-                if inode.lookup_count == 0 {
-                    entry.remove();
-                }
-            }
-        }
-
+        self.known.apply_forgets(request.forget_list());
         reply.ok()
     }
 
@@ -145,7 +117,10 @@ impl Passthrough {
         let (reply, ()) = reply.and_then(fs::symlink(request.target(), &path).await)?;
         let (reply, metadata) = reply.and_then(fs::symlink_metadata(&path).await)?;
 
-        reply.known(New(&mut self.known, Inode::new(path, metadata)), Ttl::MAX)
+        reply.known(
+            unveil(&mut self.known, Inode::new(path, metadata)),
+            Ttl::MAX,
+        )
     }
 
     async fn mkdir<'o>(&mut self, (request, reply): Op<'o, ops::Mkdir>) -> Done<'o> {
@@ -155,7 +130,10 @@ impl Passthrough {
         let (reply, ()) = reply.and_then(mkdir(&path, request.mode()))?;
         let (reply, metadata) = reply.and_then(fs::symlink_metadata(&path).await)?;
 
-        reply.known(New(&mut self.known, Inode::new(path, metadata)), Ttl::MAX)
+        reply.known(
+            unveil(&mut self.known, Inode::new(path, metadata)),
+            Ttl::MAX,
+        )
     }
 
     async fn unlink<'o>(&mut self, (request, reply): Op<'o, ops::Unlink>) -> Done<'o> {
@@ -176,65 +154,82 @@ impl Passthrough {
 
     async fn open<'o>(&mut self, (request, reply): Op<'o, ops::Open>) -> Done<'o> {
         let (reply, inode) = reply.and_then(self.known(request.ino()))?;
+        let flags = request.flags();
+        let write = flags.contains(OpenFlags::O_WRONLY) || flags.contains(OpenFlags::O_RDWR);
+
         let options = {
-            let (flags, mut options) = (request.flags(), OpenOptions::new());
-            options.custom_flags(flags.bits());
+            let mut options = OpenOptions::new();
+
+            // Truncation and appending are handled below
+            let excluded = OpenFlags::O_TRUNC | OpenFlags::O_APPEND;
+            options.custom_flags((flags - excluded).bits());
 
             if flags.contains(OpenFlags::O_RDONLY) || flags.contains(OpenFlags::O_RDWR) {
                 options.read(true);
             }
 
-            if flags.contains(OpenFlags::O_WRONLY) || flags.contains(OpenFlags::O_RDWR) {
-                options.write(true);
-            }
-
-            if flags.contains(OpenFlags::O_TRUNC) {
-                options.truncate(true);
-            }
+            options.write(write);
 
-            if flags.contains(OpenFlags::O_APPEND) {
-                options.truncate(true);
-            }
+            // std takes O_APPEND as a request for write access
+            options.append(write && request.append());
 
             options
         };
 
-        let (reply, handle) = reply.and_then(options.open(&inode.path).await)?;
+        let (mut reply, handle) = reply.and_then(options.open(&inode.path).await)?;
+
+        // std rejects O_TRUNC along with O_APPEND, thus truncation happens after opening
+        if write && request.truncate() {
+            let (next_reply, ()) = reply.and_then(handle.set_len(0).await)?;
+            reply = next_reply;
+        }
         let file = OpenFile { offset: 0, handle };
 
         reply.ok_with_handle(self.open_files.insert(file))
     }
 
     async fn read<'o>(&mut self, (request, reply): Op<'o, ops::Read>) -> Done<'o> {
-        // The read size may be larget than the file size
-        let (reply, inode) = reply.and_then(self.known(request.ino()))?;
-        let file_size = inode.metadata.len();
-
-        let (reply, file) = reply.and_then(self.open_files.get(request.handle()))?;
-        let (reply, ()) = reply.and_then(file.seek(request.offset()).await)?;
-
-        let mut buffer = Vec::new();
-        buffer.resize((request.size() as usize).min(file_size as usize), 0);
-
-        let (reply, _) = reply.and_then(file.handle.read_exact(&mut buffer).await)?;
-        reply.slice(&buffer)
+        let (reply, range) = reply.and_then(request.range().ok_or(Errno::EINVAL))?;
+        let (reply, file) = reply.and_then(
+            self.open_files
+                .get_mut(request.handle())
+                .ok_or(Errno::EINVAL),
+        )?;
+
+        // Waits for in-flight writes, since splice(2) bypasses tokio's buffering
+        let (reply, ()) = reply.and_then(file.handle.flush().await)?;
+
+        // The read size may be larger than the file size, splice(2) stops at EOF
+        let fd = file.handle.as_raw_fd();
+        reply.splice_from(fd, range.start, (range.end - range.start) as usize)
     }
 
     async fn write<'o>(&mut self, (request, reply): Op<'o, ops::Write>) -> Done<'o> {
-        let (reply, file) = reply.and_then(self.open_files.get(request.handle()))?;
-        let (reply, ()) = reply.and_then(file.seek(request.offset()).await)?;
-        let (reply, ()) = reply.and_then(file.handle.write_all(request.data()).await)?;
+        let (reply, file) = reply.and_then(
+            self.open_files
+                .get_mut(request.handle())
+                .ok_or(Errno::EINVAL),
+        )?;
+        let (reply, ()) = reply.and_then(file.write_at(request.offset(), request.data()).await)?;
 
         reply.all()
     }
 
+    fn statfs<'o>(&mut self, (_request, reply): Op<'o, ops::Statfs>) -> Done<'o> {
+        reply.unknown()
+    }
+
     fn release<'o>(&mut self, (request, reply): Op<'o, ops::Release>) -> Done<'o> {
-        self.open_files.entries.remove(&request.handle());
+        self.open_files.remove(request.handle());
         reply.ok()
     }
 
     async fn fsync<'o>(&mut self, (request, reply): Op<'o, ops::Fsync>) -> Done<'o> {
-        let (reply, file) = reply.and_then(self.open_files.get(request.handle()))?;
+        let (reply, file) = reply.and_then(
+            self.open_files
+                .get_mut(request.handle())
+                .ok_or(Errno::EINVAL),
+        )?;
         let (reply, ()) = {
             let result = if request.flags().contains(FsyncFlags::FDATASYNC) {
                 file.handle.sync_data().await
@@ -267,7 +262,11 @@ impl Passthrough {
         let (reply, parent) = reply.and_then(self.known(request.ino()))?;
         let parent_path = parent.path.clone();
 
-        let (reply, dir) = reply.and_then(self.open_dirs.get(request.handle()))?;
+        let (reply, dir) = reply.and_then(
+            self.open_dirs
+                .get_mut(request.handle())
+                .ok_or(Errno::EINVAL),
+        )?;
         let mut reply = reply.buffered(Vec::new()); //TODO: with_capacity()
 
         for (offset, entry) in dir
@@ -285,12 +284,8 @@ impl Passthrough {
                 continue;
             }
 
-            let entry = Entry {
-                offset: offset as u64 + 1,
-                name: &name,
-                ttl: Ttl::MAX,
-                inode: New(&mut self.known, Inode::new(path, metadata)),
-            };
+            let inode = unveil(&mut self.known, Inode::new(path, metadata));
+            let entry = Entry::new(&name, inode).offset(offset as u64 + 1);
 
             let (next_reply, ()) = next_reply.entry(entry)?;
             reply = next_reply;
@@ -300,18 +295,14 @@ impl Passthrough {
     }
 
     fn releasedir<'o>(&mut self, (request, reply): Op<'o, ops::Releasedir>) -> Done<'o> {
-        self.open_dirs.entries.remove(&request.handle());
+        self.open_dirs.remove(request.handle());
         reply.ok()
     }
 }
 
 impl Inode {
     fn new(path: PathBuf, metadata: Metadata) -> Self {
-        Inode {
-            path,
-            metadata,
-            lookup_count: 1,
-        }
+        Inode { path, metadata }
     }
 }
 
@@ -362,21 +353,8 @@ impl Stat for Inode {
     }
 }
 
-impl Known for New<'_> {
-    type Inode = Inode;
-
-    fn inode(&self) -> &Self::Inode {
-        &self.1
-    }
-
-    fn unveil(self) {
-        let New(known, inode) = self;
-
-        known
-            .entry(inode.ino())
-            .and_modify(|inode| inode.lookup_count += 1)
-            .or_insert(inode);
-    }
+fn unveil(known: &mut LookupTable<Inode>, inode: Inode) -> impl '_ + Known<Inode = Inode> {
+    Unveil::new(inode, |inode| known.unveil(inode.ino(), inode))
 }
 
 async fn main_loop(session: Start, mut fs: Passthrough) -> FuseResult<()> {
@@ -386,32 +364,26 @@ async fn main_loop(session: Start, mut fs: Passthrough) -> FuseResult<()> {
 
     loop {
         let result = endpoint.receive(|dispatch| async {
-            use Dispatch::*;
-
-            match dispatch {
-                Lookup(lookup) => fs.lookup(lookup.op()?).await,
-                Forget(forget) => fs.forget(forget.op()?),
-                Getattr(getattr) => fs.getattr(getattr.op()?),
-                Readlink(readlink) => fs.readlink(readlink.op()?).await,
-                Symlink(symlink) => fs.symlink(symlink.op()?).await,
-                Mkdir(mkdir) => fs.mkdir(mkdir.op()?).await,
-                Unlink(unlink) => fs.unlink(unlink.op()?).await,
-                Rmdir(rmdir) => fs.rmdir(rmdir.op()?).await,
+            dispatch!(dispatch, fs, {
+                Lookup => lookup.await,
+                Forget => forget,
+                Getattr => getattr,
+                Readlink => readlink.await,
+                Symlink => symlink.await,
+                Mkdir => mkdir.await,
+                Unlink => unlink.await,
+                Rmdir => rmdir.await,
                 //TODO: Link
-                Open(open) => fs.open(open.op()?).await,
-                Read(read) => fs.read(read.op()?).await,
-                Write(write) => fs.write(write.op()?).await,
-                Release(release) => fs.release(release.op()?),
-                Fsync(fsync) => fs.fsync(fsync.op()?).await,
-                Opendir(opendir) => fs.opendir(opendir.op()?).await,
-                Readdir(readdir) => fs.readdir(readdir.op()?).await,
-                Releasedir(releasedir) => fs.releasedir(releasedir.op()?),
-
-                dispatch => {
-                    let (_, reply) = dispatch.op();
-                    reply.not_implemented()
-                }
-            }
+                Open => open.await,
+                Read => read.await,
+                Write => write.await,
+                Statfs => statfs,
+                Release => release,
+                Fsync => fsync.await,
+                Opendir => opendir.await,
+                Readdir => readdir.await,
+                Releasedir => releasedir,
+            })
         });
 
         match result.await? {
@@ -435,12 +407,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let session = mount_sync(mountpoint, &Default::default())?;
 
     let fs = {
-        let mut known = HashMap::new();
+        let mut known = LookupTable::new();
 
         let metadata = std::fs::metadata("/").unwrap();
         let root_dev = metadata.dev();
 
-        known.insert(Ino::ROOT, Inode::new("/".into(), metadata));
+        known.unveil(Ino::ROOT, Inode::new("/".into(), metadata));
 
         Passthrough {
             known,
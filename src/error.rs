@@ -1,7 +1,18 @@
+use nix::errno::Errno;
 use thiserror::Error;
 
 pub type FuseResult<T> = Result<T, FuseError>;
 
+// Handlers reply with an Errno. Errno, std::io::Error and FuseError all implement io::Finish,
+// so any of them may be passed to Reply::and_then() or Reply::respond_with():
+//
+//   Errno          -> the errno itself
+//   io::Error      -> its OS error, or EIO if it has none (see Reply::fail_io())
+//   FuseError      -> the wrapped Errno or io::Error as above, EIO for anything else
+//
+// Errno converts into FuseError, so internals may use `?` with a single error type and leave
+// the translation to the reply boundary
+
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum FuseError {
@@ -11,6 +22,9 @@ pub enum FuseError {
     #[error("fuse handshake failed (ancient kernel?)")]
     ProtocolInit,
 
+    #[error("unsupported fuse protocol version {major}.{minor}")]
+    UnsupportedProtocol { major: u32, minor: u32 },
+
     #[error("fuse request truncated")]
     Truncated,
 
@@ -22,6 +36,9 @@ pub enum FuseError {
 
     #[error("fuse reply was trimmed on write()")]
     ShortWrite,
+
+    #[error("{0}")]
+    Errno(#[from] Errno),
 }
 
 #[derive(Debug, Error)]
@@ -30,6 +47,10 @@ pub enum MountError {
     #[error("I/O error during mount")]
     Io(#[from] std::io::Error),
 
-    #[error("fusermount failed")]
-    Fusermount,
+    // Carries whatever fusermount printed to stderr, or its exit status if nothing
+    #[error("fusermount failed: {0}")]
+    Fusermount(String),
+
+    #[error("fusermount did not answer in time")]
+    Timeout,
 }
@@ -2,41 +2,92 @@ use bytemuck::Zeroable;
 use nix::sys::stat::SFlag;
 
 use std::{
-    convert::Infallible,
+    collections::{hash_map, HashMap},
     ffi::OsStr,
     future::Future,
-    ops::{ControlFlow, FromResidual, Try},
+    marker::PhantomData,
+    os::unix::ffi::OsStrExt,
     time::{SystemTime, UNIX_EPOCH},
 };
 
+#[cfg(feature = "nightly")]
+use std::{
+    convert::Infallible,
+    ops::{ControlFlow, FromResidual, Try},
+};
+
 use super::{Done, Operation, Reply, Request};
-use crate::{proto, Errno, FuseResult};
+use crate::{
+    proto,
+    util::{page_size, OutputChain},
+    Errno, FuseError, FuseResult,
+};
 
 #[doc(no_inline)]
 pub use nix::{
     dir::Type as EntryType,
     fcntl::OFlag as OpenFlags,
+    poll::PollFlags,
     sys::stat::Mode,
     unistd::{AccessFlags, Gid, Pid, Uid},
 };
 
-pub use proto::FsyncFlags;
+pub use proto::{
+    FsyncFlags, InitFlags, IoctlFlags, Opcode, ReadFlags, ReleaseFlags, RenameFlags, SetattrValid,
+    WriteFlags,
+};
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Ino(pub u64);
 
+// Handles are chosen by the filesystem in Reply<Open>/Reply<Create> and Reply<Opendir>,
+// respectively. Distinct types keep one from being mistaken for the other
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct FileHandle(pub u64);
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct DirHandle(pub u64);
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Ttl {
     seconds: u64,
     nanoseconds: u32,
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Credentials {
+    uid: Uid,
+    gid: Gid,
+    pid: Pid,
+}
+
 #[derive(Copy, Clone, Default, Eq, PartialEq)]
 pub struct Timestamp {
     seconds: i64,
     nanoseconds: u32,
 }
 
+// Set by utimensat(2) and friends, UTIME_NOW becomes Now
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum SetTime {
+    Now,
+    At(Timestamp),
+}
+
+// The part of an xattr name before the first dot. SELinux and POSIX ACLs query security.* and
+// system.* on nearly every access, filesystems without support should fail these quickly
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum XattrNamespace {
+    Security,
+    System,
+    Trusted,
+    User,
+}
+
+// An st_mode as stored on disk, with both file type and permission bits
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RawMode(pub u32);
+
 pub enum Interruptible<'o, O: Operation<'o>, T> {
     Completed(Reply<'o, O>, T),
     Interrupted(Done<'o>),
@@ -44,8 +95,19 @@ pub enum Interruptible<'o, O: Operation<'o>, T> {
 
 pub trait Stat {
     fn ino(&self) -> Ino;
+
+    // Also decides the type bits of attrs(), which must agree with Attrs::special() if set.
+    // Inodes whose type can't be told (such as from a corrupt disk) should fail with EIO
+    // instead of reaching a reply with a made-up type
     fn inode_type(&self) -> EntryType;
+
     fn attrs(&self) -> (Attrs, Ttl);
+
+    // Uncacheable contents (such as those that change behind the kernel's back) should bypass
+    // the page cache, see ReplyOpen::ok_for()
+    fn direct_io(&self) -> bool {
+        false
+    }
 }
 
 pub trait Known {
@@ -55,6 +117,11 @@ pub trait Known {
     fn unveil(self);
 }
 
+pub struct Unveil<I, F> {
+    inode: I,
+    unveil: F,
+}
+
 pub struct Failed<'o, E>(pub Done<'o>, pub E);
 
 pub trait Finish<'o, O: Operation<'o>> {
@@ -64,24 +131,85 @@ pub trait Finish<'o, O: Operation<'o>> {
 #[derive(Clone)]
 pub struct Attrs(proto::Attrs);
 
+// Attributes in wire format, for filesystems that cache or store them, see ReplyStat::raw_attrs()
+#[derive(Copy, Clone)]
+pub struct RawAttrs(proto::Attrs);
+
 pub struct Entry<'a, K> {
     pub offset: u64,
     pub name: &'a OsStr,
     pub inode: K,
     pub ttl: Ttl,
+    pub attrs: Option<(Attrs, Ttl)>,
 }
 
 #[derive(Copy, Clone)]
 pub struct FsInfo(proto::StatfsOut);
 
+pub struct LookupTable<T> {
+    entries: HashMap<Ino, (T, u64)>,
+}
+
+// Where each directory handle's listing stopped, so that the next readdir may resume from
+// there instead of seeking from scratch
+pub struct CursorTable<C> {
+    cursors: HashMap<DirHandle, (u64, C)>,
+}
+
+// Released handles are reused, the kernel never refers to a handle after its release
+pub struct HandleTable<T, H = u64> {
+    entries: HashMap<u64, T>,
+    free: Vec<u64>,
+    next: u64,
+    _phantom: PhantomData<H>,
+}
+
 impl Ino {
     pub const NULL: Self = Ino(0);
 
+    // The kernel always refers to the root as ROOT. If the filesystem's own root has another
+    // number, the session swaps both numbers in requests and replies, so that filesystems never
+    // see the difference (see Session::root())
     pub const ROOT: Self = Ino(proto::ROOT_ID);
 
     pub fn as_raw(self) -> u64 {
         self.0
     }
+
+    // This is its own inverse, thus it works in both directions
+    pub(crate) fn swap_root(self, root: Ino) -> Ino {
+        if self == Ino::ROOT {
+            root
+        } else if self == root {
+            Ino::ROOT
+        } else {
+            self
+        }
+    }
+}
+
+impl From<u64> for FileHandle {
+    fn from(handle: u64) -> Self {
+        FileHandle(handle)
+    }
+}
+
+impl From<FileHandle> for u64 {
+    fn from(FileHandle(handle): FileHandle) -> Self {
+        handle
+    }
+}
+
+impl From<u64> for DirHandle {
+    fn from(handle: u64) -> Self {
+        DirHandle(handle)
+    }
+}
+
+impl From<DirHandle> for u64 {
+    fn from(DirHandle(handle): DirHandle) -> Self {
+        handle
+    }
 }
 
 impl std::fmt::Display for Ino {
@@ -91,6 +219,8 @@ impl std::fmt::Display for Ino {
 }
 
 impl Ttl {
+    // Not cached at all, every access goes back to the filesystem. Prior cached values are
+    // dropped as soon as a reply with this TTL arrives
     pub const NULL: Self = Ttl {
         seconds: 0,
         nanoseconds: 0,
@@ -119,6 +249,46 @@ impl Ttl {
     }
 }
 
+impl Credentials {
+    pub fn new(uid: Uid, gid: Gid, pid: Pid) -> Self {
+        Credentials { uid, gid, pid }
+    }
+
+    pub fn uid(&self) -> Uid {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    // Requests only carry the primary group, thus supplementary groups are taken from the
+    // caller's /proc/<pid>/status. This is racy: the caller may have exited or changed its
+    // groups since. A zero pid (from another pid namespace) fails with ESRCH
+    pub fn supplementary_groups(&self) -> std::io::Result<Vec<Gid>> {
+        if self.pid.as_raw() <= 0 {
+            return Err(Errno::ESRCH.into());
+        }
+
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.pid))?;
+        parse_status_groups(&status).ok_or_else(|| Errno::EIO.into())
+    }
+}
+
+fn parse_status_groups(status: &str) -> Option<Vec<Gid>> {
+    let groups = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))?;
+    groups
+        .split_whitespace()
+        .map(|gid| gid.parse().ok().map(Gid::from_raw))
+        .collect()
+}
+
 impl Timestamp {
     pub fn new(seconds: i64, nanoseconds: u32) -> Self {
         Timestamp {
@@ -126,6 +296,48 @@ impl Timestamp {
             nanoseconds,
         }
     }
+
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+}
+
+impl XattrNamespace {
+    // None for names without a dot or with a prefix unknown to Linux
+    pub fn of(name: &OsStr) -> Option<Self> {
+        let name = name.as_bytes();
+        let prefix = &name[..name.iter().position(|&byte| byte == b'.')?];
+
+        match prefix {
+            b"security" => Some(XattrNamespace::Security),
+            b"system" => Some(XattrNamespace::System),
+            b"trusted" => Some(XattrNamespace::Trusted),
+            b"user" => Some(XattrNamespace::User),
+            _ => None,
+        }
+    }
+}
+
+impl RawMode {
+    pub fn permissions(self) -> Mode {
+        Mode::from_bits_truncate(self.0 & 0o7777)
+    }
+
+    pub fn file_type(self) -> Option<EntryType> {
+        let file_type = SFlag::from_bits_truncate(self.0 & SFlag::S_IFMT.bits());
+        let entry_type = match file_type {
+            SFlag::S_IFIFO => EntryType::Fifo,
+            SFlag::S_IFCHR => EntryType::CharacterDevice,
+            SFlag::S_IFDIR => EntryType::Directory,
+            SFlag::S_IFBLK => EntryType::BlockDevice,
+            SFlag::S_IFREG => EntryType::File,
+            SFlag::S_IFLNK => EntryType::Symlink,
+            SFlag::S_IFSOCK => EntryType::Socket,
+            _ => return None,
+        };
+
+        Some(entry_type)
+    }
 }
 
 impl From<SystemTime> for Timestamp {
@@ -150,6 +362,44 @@ impl From<SystemTime> for Timestamp {
     }
 }
 
+impl<I: Stat, F: FnOnce(I)> Unveil<I, F> {
+    pub fn new(inode: I, unveil: F) -> Self {
+        Unveil { inode, unveil }
+    }
+}
+
+impl<I: Stat, F: FnOnce(I)> Known for Unveil<I, F> {
+    type Inode = I;
+
+    fn inode(&self) -> &Self::Inode {
+        &self.inode
+    }
+
+    fn unveil(self) {
+        (self.unveil)(self.inode)
+    }
+}
+
+// For filesystems that don't track lookup counts
+impl<S: Stat> Known for &S {
+    type Inode = S;
+
+    fn inode(&self) -> &Self::Inode {
+        self
+    }
+
+    fn unveil(self) {}
+}
+
+impl<'o, O: Operation<'o>, T> Interruptible<'o, O, T> {
+    pub fn into_result(self) -> Result<(Reply<'o, O>, T), Done<'o>> {
+        match self {
+            Interruptible::Completed(reply, t) => Ok((reply, t)),
+            Interruptible::Interrupted(done) => Err(done),
+        }
+    }
+}
+
 impl<'o, E> From<Failed<'o, E>> for Done<'o> {
     fn from(failed: Failed<'o, E>) -> Done<'o> {
         failed.0
@@ -164,23 +414,34 @@ impl<'o, O: Operation<'o>> Finish<'o, O> for Errno {
 
 impl<'o, O: Operation<'o>> Finish<'o, O> for std::io::Error {
     fn finish(&self, reply: Reply<'o, O>) -> Done<'o> {
-        reply.fail(
-            self.raw_os_error()
-                .map(Errno::from_i32)
-                .unwrap_or(Errno::EIO),
-        )
+        reply.fail_io(self)
+    }
+}
+
+impl<'o, O: Operation<'o>> Finish<'o, O> for FuseError {
+    fn finish(&self, reply: Reply<'o, O>) -> Done<'o> {
+        match self {
+            FuseError::Errno(errno) => reply.fail(*errno),
+            FuseError::Io(error) => reply.fail_io(error),
+            _ => reply.io_error(),
+        }
     }
 }
 
 impl<'o, O: Operation<'o>> Request<'o, O> {
     pub fn ino(&self) -> Ino {
-        Ino(self.header.ino)
+        Ino(self.header.ino).swap_root(self.root)
     }
 
     pub fn generation(&self) -> u64 {
         0
     }
 
+    pub fn opcode(&self) -> Opcode {
+        // Already validated by InHeader::from_bytes()
+        Opcode::try_from(self.header.opcode).unwrap()
+    }
+
     pub fn uid(&self) -> Uid {
         Uid::from_raw(self.header.uid)
     }
@@ -192,6 +453,10 @@ impl<'o, O: Operation<'o>> Request<'o, O> {
     pub fn pid(&self) -> Pid {
         Pid::from_raw(self.header.pid as i32)
     }
+
+    pub fn credentials(&self) -> Credentials {
+        Credentials::new(self.uid(), self.gid(), self.pid())
+    }
 }
 
 impl<'o, O: Operation<'o>> Reply<'o, O> {
@@ -218,6 +483,13 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         }
     }
 
+    // For handlers that loop without awaiting a single future, such that interruptible() doesn't
+    // fit. The interrupt must be received by another endpoint while the loop runs. Once this is
+    // true, the loop should bail out with interrupted()
+    pub fn check_interrupt(&self) -> bool {
+        self.session.is_interrupted(self.unique)
+    }
+
     pub fn and_then<T, E>(self, result: Result<T, E>) -> Result<(Self, T), Failed<'o, E>>
     where
         E: Finish<'o, O>,
@@ -231,11 +503,40 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         }
     }
 
+    pub fn respond_with<T, E, F>(self, result: Result<T, E>, f: F) -> Done<'o>
+    where
+        E: Finish<'o, O>,
+        F: FnOnce(Self, T) -> Done<'o>,
+    {
+        match result {
+            Ok(t) => f(self, t),
+            Err(error) => error.finish(self),
+        }
+    }
+
     pub fn fail(self, errno: Errno) -> Done<'o> {
         let result = self.session.fail(self.unique, errno as i32);
         self.finish(result)
     }
 
+    // Escape hatch for replies that aren't modeled yet, fragments follow the out header as-is
+    pub fn raw(self, fragments: &[&[u8]]) -> Done<'o> {
+        let result = self.session.ok(self.unique, OutputChain::tail(fragments));
+        self.finish(result)
+    }
+
+    // Errors that don't come from the OS, such as those of std::io::Error::new(), become EIO
+    pub fn fail_io(self, error: &std::io::Error) -> Done<'o> {
+        let errno = error.raw_os_error().map_or(Errno::EIO, Errno::from_i32);
+        self.fail(errno)
+    }
+
+    // For error numbers that aren't covered by Errno
+    pub fn fail_raw(self, errno: i32) -> Done<'o> {
+        let result = self.session.fail(self.unique, errno);
+        self.finish(result)
+    }
+
     pub fn not_implemented(self) -> Done<'o> {
         self.fail(Errno::ENOSYS)
     }
@@ -252,13 +553,25 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         self.fail(Errno::EINVAL)
     }
 
+    // For requests interrupted by their caller, see interruptible()
     pub fn interrupted(self) -> Done<'o> {
         self.fail(Errno::EINTR)
     }
 
-    pub(crate) fn finish(self, result: FuseResult<()>) -> Done<'o> {
-        if let Err(error) = result {
-            log::error!("Replying to request {}: {}", self.unique, error);
+    // Failing a request with an errno is a normal outcome and never logged, only transport
+    // errors are. Of these, ENOENT means that the kernel already forgot about the request (it
+    // was interrupted, or the connection aborted), which isn't the filesystem's fault
+    pub(crate) fn finish(mut self, result: FuseResult<()>) -> Done<'o> {
+        self.guard.disarm();
+
+        match result {
+            Ok(()) => (),
+
+            Err(FuseError::Io(error)) if error.raw_os_error() == Some(Errno::ENOENT as i32) => {
+                log::debug!("Request {} is gone, reply dropped", self.unique);
+            }
+
+            Err(error) => log::error!("Replying to request {}: {}", self.unique, error),
         }
 
         Done::new()
@@ -271,12 +584,14 @@ impl<'o, O: Operation<'o>> From<(Reply<'o, O>, Errno)> for Done<'o> {
     }
 }
 
+#[cfg(feature = "nightly")]
 impl<'o> FromResidual<Done<'o>> for Done<'o> {
     fn from_residual(residual: Done<'o>) -> Self {
         residual
     }
 }
 
+#[cfg(feature = "nightly")]
 impl<'o, T: Into<Done<'o>>> FromResidual<Result<Infallible, T>> for Done<'o> {
     fn from_residual(residual: Result<Infallible, T>) -> Self {
         match residual {
@@ -286,6 +601,7 @@ impl<'o, T: Into<Done<'o>>> FromResidual<Result<Infallible, T>> for Done<'o> {
     }
 }
 
+#[cfg(feature = "nightly")]
 impl<'o, O: Operation<'o>> FromResidual<Interruptible<'o, O, Infallible>> for Done<'o> {
     fn from_residual(residual: Interruptible<'o, O, Infallible>) -> Self {
         match residual {
@@ -295,6 +611,7 @@ impl<'o, O: Operation<'o>> FromResidual<Interruptible<'o, O, Infallible>> for Do
     }
 }
 
+#[cfg(feature = "nightly")]
 impl Try for Done<'_> {
     type Output = Self;
     type Residual = Self;
@@ -308,6 +625,7 @@ impl Try for Done<'_> {
     }
 }
 
+#[cfg(feature = "nightly")]
 impl<'o, O: Operation<'o>, T> FromResidual<Interruptible<'o, O, Infallible>>
     for Interruptible<'o, O, T>
 {
@@ -321,6 +639,7 @@ impl<'o, O: Operation<'o>, T> FromResidual<Interruptible<'o, O, Infallible>>
     }
 }
 
+#[cfg(feature = "nightly")]
 impl<'o, O: Operation<'o>, T> Try for Interruptible<'o, O, T> {
     type Output = (Reply<'o, O>, T);
     type Residual = Interruptible<'o, O, Infallible>;
@@ -339,6 +658,39 @@ impl<'o, O: Operation<'o>, T> Try for Interruptible<'o, O, T> {
     }
 }
 
+impl<'a, K> Entry<'a, K> {
+    // An offset of zero (the default) stands for the one following the previous entry
+    pub fn new(name: &'a OsStr, inode: K) -> Self {
+        Entry {
+            offset: 0,
+            name,
+            inode,
+            ttl: Ttl::MAX,
+            attrs: None,
+        }
+    }
+
+    #[must_use]
+    pub fn offset(self, offset: u64) -> Self {
+        Entry { offset, ..self }
+    }
+
+    #[must_use]
+    pub fn ttl(self, ttl: Ttl) -> Self {
+        Entry { ttl, ..self }
+    }
+
+    // Readdirplus takes these instead of calling Stat::attrs(), for attributes that are already
+    // at hand (such as from a previous lookup). Plain readdirs ignore them
+    #[must_use]
+    pub fn attrs(self, attrs: Attrs, ttl: Ttl) -> Self {
+        Entry {
+            attrs: Some((attrs, ttl)),
+            ..self
+        }
+    }
+}
+
 impl Attrs {
     #[must_use]
     pub fn size(self, size: u64) -> Self {
@@ -354,19 +706,29 @@ impl Attrs {
         })
     }
 
+    // Keeps the file type set by special(), if any
     #[must_use]
     pub fn mode(self, mode: Mode) -> Self {
         Attrs(proto::Attrs {
-            mode: mode.bits(),
+            mode: (self.0.mode & SFlag::S_IFMT.bits()) | mode.bits(),
             ..self.0
         })
     }
 
+    // Allocated 512-byte blocks, as shown by du(1). This is never derived from the size, since
+    // sparse files occupy less than that. See blocks_from_size() for dense files
     #[must_use]
     pub fn blocks(self, blocks: u64) -> Self {
         Attrs(proto::Attrs { blocks, ..self.0 })
     }
 
+    // Reports full allocation for the size set so far, which is only right for dense files
+    #[must_use]
+    pub fn blocks_from_size(self) -> Self {
+        let blocks = self.0.size.div_ceil(512);
+        Attrs(proto::Attrs { blocks, ..self.0 })
+    }
+
     #[must_use]
     pub fn block_size(self, block_size: u32) -> Self {
         Attrs(proto::Attrs {
@@ -383,6 +745,18 @@ impl Attrs {
         })
     }
 
+    // Sets the device of special files along with their type, which must match the inode's
+    // Stat::inode_type(). This is checked in debug builds
+    #[must_use]
+    pub fn special(self, inode_type: EntryType, device: u32) -> Self {
+        let mode = self.0.mode & !SFlag::S_IFMT.bits();
+        Attrs(proto::Attrs {
+            mode: mode | type_flag(inode_type).bits(),
+            rdev: device,
+            ..self.0
+        })
+    }
+
     #[must_use]
     pub fn times(self, access: Timestamp, modify: Timestamp, change: Timestamp) -> Self {
         Attrs(proto::Attrs {
@@ -404,35 +778,148 @@ impl Attrs {
         })
     }
 
-    pub(crate) fn finish(self, inode: &impl Stat) -> proto::Attrs {
-        let Ino(ino) = inode.ino();
-        let inode_type = match inode.inode_type() {
-            EntryType::Fifo => SFlag::S_IFIFO,
-            EntryType::CharacterDevice => SFlag::S_IFCHR,
-            EntryType::Directory => SFlag::S_IFDIR,
-            EntryType::BlockDevice => SFlag::S_IFBLK,
-            EntryType::File => SFlag::S_IFREG,
-            EntryType::Symlink => SFlag::S_IFLNK,
-            EntryType::Socket => SFlag::S_IFSOCK,
+    // Standard owner/group/other permission check, as done by default_permissions. The inode
+    // type is not part of Attrs, thus it must be passed along (see Stat::inode_type()). Only the
+    // caller's primary group is considered, see check_access_with_groups()
+    pub fn check_access(
+        &self,
+        inode_type: EntryType,
+        cred: &Credentials,
+        mask: AccessFlags,
+    ) -> bool {
+        self.check_access_with_groups(inode_type, cred, &[], mask)
+    }
+
+    // Same as check_access(), with the group class also applying to members of `groups` (such
+    // as from Credentials::supplementary_groups())
+    pub fn check_access_with_groups(
+        &self,
+        inode_type: EntryType,
+        cred: &Credentials,
+        groups: &[Gid],
+        mask: AccessFlags,
+    ) -> bool {
+        let mode = self.0.mode;
+        let wanted = mask & (AccessFlags::R_OK | AccessFlags::W_OK | AccessFlags::X_OK);
+
+        if cred.uid().is_root() {
+            // Root may execute only if someone else could, but may always search directories
+            let is_dir = matches!(inode_type, EntryType::Directory);
+            return !wanted.contains(AccessFlags::X_OK) || is_dir || mode & 0o111 != 0;
+        }
+
+        let granted = if cred.uid().as_raw() == self.0.uid {
+            mode >> 6
+        } else if cred.gid().as_raw() == self.0.gid
+            || groups.iter().any(|gid| gid.as_raw() == self.0.gid)
+        {
+            mode >> 3
+        } else {
+            mode
+        };
+
+        let granted = AccessFlags::from_bits_truncate((granted & 0o7) as i32);
+        granted.contains(wanted)
+    }
+
+    // The inode number and type are still taken from the Stat when replying
+    pub fn from_raw(raw: RawAttrs) -> Self {
+        Attrs(raw.0)
+    }
+
+    pub fn into_raw(self, inode: &impl Stat) -> RawAttrs {
+        // Swapping happens when replying, since the root may only be known after Init
+        RawAttrs(self.finish(inode, Ino::ROOT))
+    }
+
+    pub(crate) fn finish(self, inode: &impl Stat, root: Ino) -> proto::Attrs {
+        let Ino(ino) = inode.ino().swap_root(root);
+        let inode_type = type_flag(inode.inode_type());
+
+        let special = self.0.mode & SFlag::S_IFMT.bits();
+        debug_assert!(
+            special == 0 || special == inode_type.bits(),
+            "Inode {} has type {:?}, but special() says {:o}",
+            ino,
+            inode.inode_type(),
+            special
+        );
+
+        debug_assert!(
+            self.0.rdev == 0 || matches!(inode_type, SFlag::S_IFCHR | SFlag::S_IFBLK),
+            "Inode {} of type {:?} has a device number",
+            ino,
+            inode.inode_type()
+        );
+
+        // A zero st_blksize breaks st_blocks-based tools such as du(1)
+        let blksize = match self.0.blksize {
+            0 => page_size().try_into().unwrap(),
+            blksize => blksize,
         };
 
         proto::Attrs {
             ino,
             mode: self.0.mode | inode_type.bits(),
+            blksize,
             ..self.0
         }
     }
 }
 
+fn type_flag(inode_type: EntryType) -> SFlag {
+    match inode_type {
+        EntryType::Fifo => SFlag::S_IFIFO,
+        EntryType::CharacterDevice => SFlag::S_IFCHR,
+        EntryType::Directory => SFlag::S_IFDIR,
+        EntryType::BlockDevice => SFlag::S_IFBLK,
+        EntryType::File => SFlag::S_IFREG,
+        EntryType::Symlink => SFlag::S_IFLNK,
+        EntryType::Socket => SFlag::S_IFSOCK,
+    }
+}
+
+impl RawAttrs {
+    // For attributes that were stored as-is, such as by as_bytes(). Alignment doesn't matter
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut attrs = proto::Attrs::zeroed();
+        let attrs_bytes = bytemuck::bytes_of_mut(&mut attrs);
+        if bytes.len() != attrs_bytes.len() {
+            return None;
+        }
+
+        attrs_bytes.copy_from_slice(bytes);
+        Some(RawAttrs(attrs))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::bytes_of(&self.0)
+    }
+
+    pub(crate) fn finish(self, root: Ino) -> proto::Attrs {
+        let Ino(ino) = Ino(self.0.ino).swap_root(root);
+        proto::Attrs { ino, ..self.0 }
+    }
+}
+
 impl Default for Attrs {
     fn default() -> Self {
-        Attrs(Zeroable::zeroed()).links(1)
+        Attrs(Zeroable::zeroed())
+            .links(1)
+            .block_size(page_size().try_into().unwrap())
     }
 }
 
 impl FsInfo {
+    // Counts must satisfy available <= free <= total, or df(1) reports negative used space.
+    // This is checked in debug builds, a typical culprit being underflowed reserved-block math
     #[must_use]
     pub fn blocks(self, size: u32, total: u64, free: u64, available: u64) -> Self {
+        debug_assert!(
+            available <= free && free <= total,
+            "bad block counts: available={available}, free={free}, total={total}"
+        );
+
         FsInfo(proto::StatfsOut {
             bsize: size,
             blocks: total,
@@ -444,6 +931,11 @@ impl FsInfo {
 
     #[must_use]
     pub fn inodes(self, total: u64, free: u64) -> Self {
+        debug_assert!(
+            free <= total,
+            "bad inode counts: free={free}, total={total}"
+        );
+
         FsInfo(proto::StatfsOut {
             files: total,
             ffree: free,
@@ -451,6 +943,14 @@ impl FsInfo {
         })
     }
 
+    #[must_use]
+    pub fn fragment_size(self, size: u32) -> Self {
+        FsInfo(proto::StatfsOut {
+            frsize: size,
+            ..self.0
+        })
+    }
+
     #[must_use]
     pub fn max_filename(self, max: u32) -> Self {
         FsInfo(proto::StatfsOut {
@@ -471,3 +971,425 @@ impl From<FsInfo> for proto::StatfsOut {
         statfs
     }
 }
+
+impl<T> LookupTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, ino: Ino) -> Option<&T> {
+        self.entries.get(&ino).map(|(value, _)| value)
+    }
+
+    pub fn get_mut(&mut self, ino: Ino) -> Option<&mut T> {
+        self.entries.get_mut(&ino).map(|(value, _)| value)
+    }
+
+    pub fn lookup_count(&self, ino: Ino) -> u64 {
+        self.entries.get(&ino).map_or(0, |(_, count)| *count)
+    }
+
+    // If the inode is already known, `value` is dropped and the old one is kept
+    pub fn unveil(&mut self, ino: Ino, value: T) {
+        self.entries
+            .entry(ino)
+            .and_modify(|(_, count)| *count += 1)
+            .or_insert((value, 1));
+    }
+
+    pub fn forget(&mut self, ino: Ino, count: u64) -> Option<T> {
+        match self.entries.entry(ino) {
+            hash_map::Entry::Occupied(mut entry) => {
+                let (_, lookups) = entry.get_mut();
+                *lookups = lookups.saturating_sub(count);
+
+                if *lookups == 0 {
+                    Some(entry.remove().0)
+                } else {
+                    None
+                }
+            }
+
+            hash_map::Entry::Vacant(_) => {
+                log::debug!("Attempted to forget unknown inode {}", ino);
+                None
+            }
+        }
+    }
+
+    pub fn apply_forgets<I>(&mut self, list: I)
+    where
+        I: IntoIterator<Item = (Ino, u64)>,
+    {
+        for (ino, count) in list {
+            self.forget(ino, count);
+        }
+    }
+}
+
+impl<T> Default for LookupTable<T> {
+    fn default() -> Self {
+        LookupTable {
+            entries: Default::default(),
+        }
+    }
+}
+
+impl<C> CursorTable<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces any other cursor of the same handle
+    pub fn stash(&mut self, handle: DirHandle, offset: u64, cursor: C) {
+        self.cursors.insert(handle, (offset, cursor));
+    }
+
+    // A cursor for another offset is stale (after a seekdir(), for instance) and gets dropped
+    pub fn take(&mut self, handle: DirHandle, offset: u64) -> Option<C> {
+        match self.cursors.remove(&handle) {
+            Some((stashed, cursor)) if stashed == offset => Some(cursor),
+            _ => None,
+        }
+    }
+
+    pub fn release(&mut self, handle: DirHandle) {
+        self.cursors.remove(&handle);
+    }
+}
+
+impl<C> Default for CursorTable<C> {
+    fn default() -> Self {
+        CursorTable {
+            cursors: Default::default(),
+        }
+    }
+}
+
+impl<T, H: From<u64> + Into<u64>> HandleTable<T, H> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, handle: H) -> Option<&T> {
+        self.entries.get(&handle.into())
+    }
+
+    pub fn get_mut(&mut self, handle: H) -> Option<&mut T> {
+        self.entries.get_mut(&handle.into())
+    }
+
+    pub fn insert(&mut self, value: T) -> H {
+        let handle = self.free.pop().unwrap_or_else(|| {
+            let handle = self.next;
+            self.next += 1;
+            handle
+        });
+
+        self.entries.insert(handle, value);
+        handle.into()
+    }
+
+    pub fn remove(&mut self, handle: H) -> Option<T> {
+        let handle = handle.into();
+        let value = self.entries.remove(&handle);
+        if value.is_some() {
+            self.free.push(handle);
+        } else {
+            log::debug!("Attempted to release unknown handle {}", handle);
+        }
+
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T, H> Default for HandleTable<T, H> {
+    fn default() -> Self {
+        HandleTable {
+            entries: Default::default(),
+            free: Vec::new(),
+            next: 0,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_independent_of_size() {
+        let sparse = Attrs::default().size(1 << 20).blocks(8);
+        assert_eq!(sparse.0.blocks, 8);
+
+        let dense = Attrs::default().size(1025).blocks_from_size();
+        assert_eq!(dense.0.blocks, 3);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "bad block counts")]
+    fn fs_info_rejects_available_above_free() {
+        let _ = FsInfo::default().blocks(4096, 100, 10, 20);
+    }
+
+    #[test]
+    fn swap_root_is_symmetric() {
+        let root = Ino(2);
+
+        assert_eq!(Ino::ROOT.swap_root(root), root);
+        assert_eq!(root.swap_root(root), Ino::ROOT);
+        assert_eq!(Ino(3).swap_root(root), Ino(3));
+        assert_eq!(Ino(3).swap_root(Ino::ROOT), Ino(3));
+        assert_eq!(Ino::ROOT.swap_root(Ino::ROOT), Ino::ROOT);
+    }
+
+    #[test]
+    fn raw_mode_splits_type_and_permissions() {
+        let mode = RawMode(0o40755);
+        assert_eq!(mode.file_type(), Some(EntryType::Directory));
+        assert_eq!(mode.permissions().bits(), 0o755);
+
+        let mode = RawMode(0o104711);
+        assert_eq!(mode.file_type(), Some(EntryType::File));
+        assert_eq!(mode.permissions().bits(), 0o4711);
+
+        assert_eq!(RawMode(0o120777).file_type(), Some(EntryType::Symlink));
+        assert_eq!(RawMode(0o644).file_type(), None);
+    }
+
+    fn check(mode: u32, inode_type: EntryType, uid: u32, gid: u32, mask: AccessFlags) -> bool {
+        let attrs = Attrs::default()
+            .owner(Uid::from_raw(1000), Gid::from_raw(100))
+            .mode(Mode::from_bits_truncate(mode));
+
+        let cred = Credentials::new(Uid::from_raw(uid), Gid::from_raw(gid), Pid::from_raw(1));
+        attrs.check_access(inode_type, &cred, mask)
+    }
+
+    #[test]
+    fn check_access_owner() {
+        let rw = AccessFlags::R_OK | AccessFlags::W_OK;
+
+        assert!(check(0o600, EntryType::File, 1000, 100, rw));
+        assert!(!check(0o600, EntryType::File, 1000, 100, AccessFlags::X_OK));
+        assert!(!check(0o077, EntryType::File, 1000, 100, AccessFlags::R_OK));
+    }
+
+    #[test]
+    fn check_access_group() {
+        assert!(check(0o040, EntryType::File, 1001, 100, AccessFlags::R_OK));
+        assert!(!check(0o040, EntryType::File, 1001, 100, AccessFlags::W_OK));
+        assert!(!check(0o407, EntryType::File, 1001, 100, AccessFlags::R_OK));
+    }
+
+    #[test]
+    fn check_access_other() {
+        assert!(check(0o005, EntryType::File, 1001, 101, AccessFlags::X_OK));
+        assert!(!check(0o005, EntryType::File, 1001, 101, AccessFlags::W_OK));
+        assert!(!check(0o770, EntryType::File, 1001, 101, AccessFlags::R_OK));
+    }
+
+    #[test]
+    fn check_access_root() {
+        let rw = AccessFlags::R_OK | AccessFlags::W_OK;
+
+        assert!(check(0o000, EntryType::File, 0, 0, rw));
+        assert!(!check(0o600, EntryType::File, 0, 0, AccessFlags::X_OK));
+        assert!(check(0o001, EntryType::File, 0, 0, AccessFlags::X_OK));
+        assert!(check(0o000, EntryType::Directory, 0, 0, AccessFlags::X_OK));
+    }
+
+    #[test]
+    fn check_access_supplementary_groups() {
+        let attrs = Attrs::default()
+            .owner(Uid::from_raw(1000), Gid::from_raw(100))
+            .mode(Mode::from_bits_truncate(0o070));
+
+        let cred = Credentials::new(Uid::from_raw(1001), Gid::from_raw(101), Pid::from_raw(1));
+        let groups = [Gid::from_raw(27), Gid::from_raw(100)];
+        let (file, read) = (EntryType::File, AccessFlags::R_OK);
+
+        assert!(!attrs.check_access(file, &cred, read));
+        assert!(attrs.check_access_with_groups(file, &cred, &groups, read));
+        assert!(!attrs.check_access_with_groups(file, &cred, &groups[..1], read));
+    }
+
+    #[test]
+    fn status_groups_are_parsed() {
+        let status = "Name:\tcat\nUid:\t1000\t1000\t1000\t1000\nGroups:\t10 100 \nNgid:\t0\n";
+        let groups = parse_status_groups(status).unwrap();
+        assert_eq!(groups, [Gid::from_raw(10), Gid::from_raw(100)]);
+
+        assert_eq!(parse_status_groups("Groups:\t\n"), Some(vec![]));
+        assert_eq!(parse_status_groups("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn lookup_table_counts_unveils() {
+        let mut table = LookupTable::new();
+        table.unveil(Ino(2), "first");
+        table.unveil(Ino(2), "second");
+
+        assert_eq!(table.lookup_count(Ino(2)), 2);
+        assert_eq!(table.get(Ino(2)), Some(&"first"));
+
+        assert_eq!(table.forget(Ino(2), 1), None);
+        assert_eq!(table.lookup_count(Ino(2)), 1);
+    }
+
+    #[test]
+    fn lookup_table_removes_at_zero() {
+        let mut table = LookupTable::new();
+        table.unveil(Ino(2), "file");
+        table.unveil(Ino(2), "file");
+
+        assert_eq!(table.forget(Ino(2), 2), Some("file"));
+        assert_eq!(table.lookup_count(Ino(2)), 0);
+        assert_eq!(table.get(Ino(2)), None);
+    }
+
+    #[test]
+    fn lookup_table_ignores_unknown_forgets() {
+        let mut table = LookupTable::new();
+        table.unveil(Ino(2), "file");
+
+        assert_eq!(table.forget(Ino(3), 1), None);
+        table.apply_forgets([(Ino(3), 1), (Ino(4), u64::MAX)]);
+
+        assert_eq!(table.lookup_count(Ino(2)), 1);
+        assert_eq!(table.lookup_count(Ino(3)), 0);
+        assert_eq!(table.get(Ino(3)), None);
+    }
+
+    #[test]
+    fn lookup_table_forget_saturates() {
+        let mut table = LookupTable::new();
+        table.unveil(Ino(2), "file");
+
+        assert_eq!(table.forget(Ino(2), u64::MAX), Some("file"));
+        assert_eq!(table.get(Ino(2)), None);
+    }
+
+    #[test]
+    fn handle_table_reuses_released_handles() {
+        let mut table = HandleTable::<_, FileHandle>::new();
+        let (a, b) = (table.insert('a'), table.insert('b'));
+        assert_ne!(a, b);
+
+        assert_eq!(table.remove(a), Some('a'));
+        assert_eq!(table.remove(a), None);
+        assert_eq!(table.get(a), None);
+
+        let c = table.insert('c');
+        assert_eq!(c, a);
+        assert_eq!(table.get(c), Some(&'c'));
+        assert_eq!(table.get(b), Some(&'b'));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn raw_attrs_swap_root_on_reply() {
+        struct Root;
+
+        impl Stat for Root {
+            fn ino(&self) -> Ino {
+                Ino(2)
+            }
+
+            fn inode_type(&self) -> EntryType {
+                EntryType::Directory
+            }
+
+            fn attrs(&self) -> (Attrs, Ttl) {
+                (Attrs::default(), Ttl::NULL)
+            }
+        }
+
+        let raw = Attrs::default()
+            .mode(Mode::from_bits_truncate(0o755))
+            .into_raw(&Root);
+
+        let attrs = raw.finish(Ino(2));
+        assert_eq!(attrs.ino, Ino::ROOT.as_raw());
+        assert_eq!(attrs.mode, 0o40755);
+        assert_eq!(raw.finish(Ino::ROOT).ino, 2);
+    }
+
+    #[test]
+    fn raw_attrs_round_trip_through_bytes() {
+        struct Link;
+
+        impl Stat for Link {
+            fn ino(&self) -> Ino {
+                Ino(42)
+            }
+
+            fn inode_type(&self) -> EntryType {
+                EntryType::Symlink
+            }
+
+            fn attrs(&self) -> (Attrs, Ttl) {
+                (Attrs::default(), Ttl::NULL)
+            }
+        }
+
+        let raw = Attrs::default().size(13).links(2).into_raw(&Link);
+
+        // Stored bytes need not be aligned
+        let mut stored = vec![0];
+        stored.extend_from_slice(raw.as_bytes());
+
+        let loaded = RawAttrs::from_bytes(&stored[1..]).unwrap();
+        assert_eq!(loaded.as_bytes(), raw.as_bytes());
+        assert!(RawAttrs::from_bytes(&stored).is_none());
+
+        let attrs = Attrs::from_raw(loaded).finish(&Link, Ino::ROOT);
+        assert_eq!((attrs.ino, attrs.size, attrs.nlink), (42, 13, 2));
+        assert_eq!(attrs.mode & SFlag::S_IFMT.bits(), SFlag::S_IFLNK.bits());
+    }
+
+    #[test]
+    fn special_sets_type_and_device() {
+        let attrs = Attrs::default()
+            .special(EntryType::CharacterDevice, 0x0103)
+            .mode(Mode::from_bits_truncate(0o666));
+
+        assert_eq!(attrs.0.mode, 0o20666);
+        assert_eq!(attrs.0.rdev, 0x0103);
+
+        let attrs = attrs.special(EntryType::BlockDevice, 0x0800);
+        assert_eq!(
+            RawMode(attrs.0.mode).file_type(),
+            Some(EntryType::BlockDevice)
+        );
+        assert_eq!(RawMode(attrs.0.mode).permissions().bits(), 0o666);
+    }
+
+    #[test]
+    fn cursor_table_matches_offsets() {
+        let mut cursors = CursorTable::new();
+
+        cursors.stash(DirHandle(1), 10, 'a');
+        cursors.stash(DirHandle(2), 20, 'b');
+        assert_eq!(cursors.take(DirHandle(1), 10), Some('a'));
+        assert_eq!(cursors.take(DirHandle(1), 10), None);
+
+        assert_eq!(cursors.take(DirHandle(2), 0), None);
+        assert_eq!(cursors.take(DirHandle(2), 20), None);
+
+        cursors.stash(DirHandle(3), 30, 'c');
+        cursors.stash(DirHandle(3), 40, 'd');
+        cursors.release(DirHandle(4));
+        assert_eq!(cursors.take(DirHandle(3), 40), Some('d'));
+    }
+}
@@ -3,7 +3,7 @@
 //! `blown-fuse`
 
 #![forbid(unsafe_code)]
-#![feature(try_trait_v2)]
+#![cfg_attr(feature = "nightly", feature(try_trait_v2))]
 
 #[cfg(not(target_os = "linux"))]
 compile_error!("Unsupported OS");
@@ -34,6 +34,7 @@ pub type Op<'o, O = ops::Any> = (Request<'o, O>, Reply<'o, O>);
 pub struct Request<'o, O: Operation<'o>> {
     header: proto::InHeader,
     body: O::RequestBody,
+    root: io::Ino,
 }
 
 #[must_use]
@@ -41,6 +42,7 @@ pub struct Reply<'o, O: Operation<'o>> {
     session: &'o session::Session,
     unique: u64,
     state: O::ReplyState,
+    guard: session::ReplyGuard<'o>,
 }
 
 #[must_use]
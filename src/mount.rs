@@ -7,19 +7,31 @@ use std::{
         net::UnixStream,
     },
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output, Stdio},
+    time::{Duration, Instant},
 };
 
 use nix::{
     self, cmsg_space,
-    fcntl::{fcntl, FcntlArg, FdFlag},
-    sys::socket::{recvmsg, ControlMessageOwned, MsgFlags},
+    errno::Errno,
+    fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        socket::{recvmsg, ControlMessageOwned, MsgFlags},
+        stat::Mode,
+    },
 };
 
 use crate::{error::MountError, session::Start, util::DumbFd};
 
 #[derive(Default)]
-pub struct Options(OsString);
+pub struct Options {
+    options: OsString,
+    auto_unmount: bool,
+    keep_mounted: bool,
+    fusermount: Option<OsString>,
+    timeout: Option<Duration>,
+}
 
 impl Options {
     pub fn fs_name<O: AsRef<OsStr>>(&mut self, fs_name: O) -> &mut Self {
@@ -30,6 +42,32 @@ impl Options {
         self.push("ro")
     }
 
+    // fusermount3 unmounts once the session is gone, even if the process is killed. This
+    // replaces the unmount that would otherwise occur when dropping the Start or Session
+    pub fn auto_unmount(&mut self) -> &mut Self {
+        self.auto_unmount = true;
+        self.push("auto_unmount")
+    }
+
+    // Leaves the filesystem mounted (albeit disconnected) after the Start or Session is dropped
+    pub fn keep_mounted(&mut self) -> &mut Self {
+        self.keep_mounted = true;
+        self
+    }
+
+    // Mount helper to run instead of fusermount3 from PATH, it's also used to unmount
+    pub fn fusermount<P: AsRef<OsStr>>(&mut self, path: P) -> &mut Self {
+        self.fusermount = Some(path.as_ref().to_owned());
+        self
+    }
+
+    // Gives up on (and kills) a fusermount that doesn't pass the session back in time, such as
+    // one that hangs waiting for input. There is no timeout by default
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub fn push<O: AsRef<OsStr>>(&mut self, option: O) -> &mut Self {
         self.push_parts(&[option.as_ref()])
     }
@@ -57,14 +95,14 @@ impl Options {
     }
 
     fn push_parts(&mut self, segment: &[&OsStr]) -> &mut Self {
-        if !self.0.is_empty() {
-            self.0.push(",");
+        if !self.options.is_empty() {
+            self.options.push(",");
         }
 
-        let start = self.0.as_bytes().len();
-        segment.iter().for_each(|part| self.0.push(part));
+        let start = self.options.as_bytes().len();
+        segment.iter().for_each(|part| self.options.push(part));
 
-        let bytes = self.0.as_bytes();
+        let bytes = self.options.as_bytes();
         let last = bytes.len() - 1;
 
         assert!(
@@ -102,56 +140,228 @@ where
     )
     .unwrap();
 
-    let mut command = Command::new(FUSERMOUNT_CMD);
-    if !options.0.is_empty() {
-        command.args(&[OsStr::new("-o"), &options.0]);
+    let fusermount_cmd = options
+        .fusermount
+        .clone()
+        .unwrap_or_else(|| FUSERMOUNT_CMD.into());
+
+    let mut command = Command::new(&fusermount_cmd);
+    if !options.options.is_empty() {
+        command.args([OsStr::new("-o"), &options.options]);
     }
 
-    command.args(&[OsStr::new("--"), mountpoint.as_ref().as_ref()]);
+    // With auto_unmount, fusermount3 may still report errors long after mounting, at which
+    // point nobody would be reading the pipe
+    if !options.auto_unmount {
+        command.stderr(Stdio::piped());
+    }
+
+    command.args([OsStr::new("--"), mountpoint.as_ref().as_ref()]);
     let mut fusermount = command.env("_FUSE_COMMFD", right_fd.to_string()).spawn()?;
 
     // recvmsg() should fail if fusermount exits (last open fd is closed)
     drop(right_side);
 
+    if let Some(timeout) = options.timeout {
+        if !wait_readable(&left_side, timeout)? {
+            let _ = fusermount.kill();
+            fusermount.wait()?;
+
+            return Err(MountError::Timeout);
+        }
+    }
+
     let session_fd = {
         let mut buffer = cmsg_space!(RawFd);
-        let message = recvmsg(
-            left_side.as_raw_fd(),
-            &[],
-            Some(&mut buffer),
-            MsgFlags::empty(),
-        )
-        .map_err(io::Error::from)?;
-
-        let session_fd = match message.cmsgs().next() {
-            Some(ControlMessageOwned::ScmRights(fds)) => fds.into_iter().next(),
-            _ => None,
+        let message = loop {
+            let flags = MsgFlags::empty();
+            match recvmsg(left_side.as_raw_fd(), &[], Some(&mut buffer), flags) {
+                Err(Errno::EINTR) => continue,
+                result => break result.map_err(io::Error::from)?,
+            }
         };
 
-        session_fd.ok_or(MountError::Fusermount)
+        match message.cmsgs().next() {
+            Some(ControlMessageOwned::ScmRights(fds)) => fds.into_iter().next(),
+            _ => None,
+        }
     };
 
     match session_fd {
-        Ok(session_fd) => Ok(Start::new(DumbFd(session_fd), mountpoint.into())),
+        Some(session_fd) => {
+            // With auto_unmount, fusermount3 lingers until its end of the socket is closed
+            let fusermount_comm = options.auto_unmount.then_some(left_side);
+            let unmount_on_drop = !options.auto_unmount && !options.keep_mounted;
+
+            Ok(Start::new(
+                DumbFd(session_fd),
+                mountpoint.into(),
+                fusermount_cmd,
+                unmount_on_drop,
+                fusermount_comm,
+            ))
+        }
 
-        Err(error) => {
+        None => {
             drop(left_side);
-            fusermount.wait()?;
-            Err(error)
+            Err(fusermount_error(fusermount.wait_with_output()?))
         }
     }
 }
 
-pub(crate) fn unmount_sync<M: AsRef<OsStr>>(mountpoint: M) -> Result<(), MountError> {
-    let status = Command::new(FUSERMOUNT_CMD)
-        .args(&[OsStr::new("-zuq"), OsStr::new("--"), mountpoint.as_ref()])
-        .status()?;
+// CUSE devices are created by the kernel once the session starts, see Start::start_cuse()
+pub fn open_cuse_sync() -> Result<Start, MountError> {
+    let flags = OFlag::O_RDWR | OFlag::O_CLOEXEC;
+    let session_fd = open(CUSE_DEVICE, flags, Mode::empty()).map_err(io::Error::from)?;
+
+    let fusermount_cmd = FUSERMOUNT_CMD.into();
+    Ok(Start::new(
+        DumbFd(session_fd),
+        PathBuf::new(),
+        fusermount_cmd,
+        false,
+        None,
+    ))
+}
+
+pub(crate) fn unmount_sync<M: AsRef<OsStr>>(
+    fusermount_cmd: &OsStr,
+    mountpoint: M,
+) -> Result<(), MountError> {
+    let output = Command::new(fusermount_cmd)
+        .args([OsStr::new("-zuq"), OsStr::new("--"), mountpoint.as_ref()])
+        .stdin(Stdio::null())
+        .output()?;
 
-    if status.success() {
+    if output.status.success() {
         Ok(())
     } else {
-        Err(MountError::Fusermount)
+        Err(fusermount_error(output))
+    }
+}
+
+// Aborts the connection through fusectl, as `echo 1 > /sys/fs/fuse/connections/N/abort` would.
+// The mountpoint itself is never stat()ed, since that would block on the aborted filesystem
+pub(crate) fn abort_sync(mountpoint: &Path) -> io::Result<()> {
+    let mountpoint = match (mountpoint.parent(), mountpoint.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            parent.canonicalize()?.join(name)
+        }
+
+        (_, Some(name)) => std::env::current_dir()?.join(name),
+        _ => mountpoint.to_owned(),
+    };
+
+    let mountinfo = std::fs::read("/proc/self/mountinfo")?;
+    let connection = connection_id(&mountinfo, &mountpoint).ok_or(Errno::ENOTCONN)?;
+
+    std::fs::write(format!("{}/{}/abort", FUSECTL_DIR, connection), "1")
+}
+
+// Connections are named after the superblock's device number (see fs/fuse/control.c), which is
+// the major:minor of the topmost (thus last) fuse mount on the mountpoint
+fn connection_id(mountinfo: &[u8], mountpoint: &Path) -> Option<u32> {
+    let mountpoint = mountpoint.as_os_str().as_bytes();
+
+    mountinfo.rsplit(|&byte| byte == b'\n').find_map(|line| {
+        // id parent major:minor root mountpoint options [optional...] - fstype ...
+        let fields: Vec<_> = line.split(|&byte| byte == b' ').collect();
+        let separator = fields.iter().position(|&field| field == b"-")?;
+        let fstype = fields.get(separator + 1)?;
+
+        let is_fuse = fstype.starts_with(b"fuse");
+        if !is_fuse || unescape_mountinfo(fields.get(4)?) != mountpoint {
+            return None;
+        }
+
+        let device = std::str::from_utf8(fields.get(2)?).ok()?;
+        let (major, minor) = device.split_once(':')?;
+        let (major, minor): (u32, u32) = (major.parse().ok()?, minor.parse().ok()?);
+
+        // The kernel's internal dev_t, not the one from userspace
+        Some(major << 20 | minor)
+    })
+}
+
+// Spaces, tabs, newlines and backslashes are octal-escaped
+fn unescape_mountinfo(field: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut rest = field;
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let octal = tail.get(..3).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+
+        match (byte, octal) {
+            (b'\\', Some(escaped)) => {
+                unescaped.push(escaped);
+                rest = &tail[3..];
+            }
+
+            _ => {
+                unescaped.push(byte);
+                rest = tail;
+            }
+        }
     }
+
+    unescaped
+}
+
+// Also true if fusermount exited, since the socket is then readable (at EOF)
+fn wait_readable(socket: &UnixStream, timeout: Duration) -> Result<bool, MountError> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let millis = remaining.as_millis().try_into().unwrap_or(i32::MAX);
+
+        let mut fds = [PollFd::new(socket.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, millis) {
+            Ok(0) => break Ok(false),
+            Ok(_) => break Ok(true),
+            Err(Errno::EINTR) => continue,
+            Err(errno) => break Err(io::Error::from(errno).into()),
+        }
+    }
+}
+
+fn fusermount_error(output: Output) -> MountError {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let message = match stderr.trim() {
+        "" => output.status.to_string(),
+        stderr => stderr.to_owned(),
+    };
+
+    MountError::Fusermount(message)
 }
 
 const FUSERMOUNT_CMD: &str = "fusermount3";
+const CUSE_DEVICE: &str = "/dev/cuse";
+const FUSECTL_DIR: &str = "/sys/fs/fuse/connections";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &[u8] = b"\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+41 22 0:38 / /mnt/a\\040b rw,nosuid,nodev,relatime shared:2 - fuse.memfs memfs rw,user_id=0
+47 22 0:45 / /mnt/c rw,nosuid,nodev,relatime - fuse memfs rw,user_id=1000
+52 47 0:51 / /mnt/c rw,nosuid,nodev,relatime - fuse.ext2 ext2 rw,user_id=1000
+";
+
+    #[test]
+    fn connection_ids_from_mountinfo() {
+        assert_eq!(connection_id(MOUNTINFO, Path::new("/mnt/a b")), Some(38));
+        assert_eq!(connection_id(MOUNTINFO, Path::new("/mnt/a\\040b")), None);
+        assert_eq!(connection_id(MOUNTINFO, Path::new("/")), None);
+    }
+
+    #[test]
+    fn stacked_mounts_abort_the_top_one() {
+        assert_eq!(connection_id(MOUNTINFO, Path::new("/mnt/c")), Some(51));
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 };
 
 use crate::{
-    io::{Entry, EntryType, Ino, Interruptible, Known, Stat, Ttl},
+    io::{DirHandle, Entry, EntryType, Ino, Interruptible, Known, Stat, Ttl},
     proto,
     sealed::Sealed,
     Done, Errno, Operation, Reply, Request,
@@ -30,17 +30,40 @@ pub enum Readdir {}
 pub struct BufferedReaddir<B>(Infallible, PhantomData<B>);
 
 pub trait ReplyFound<'o>: ReplyKnown<'o> {
+    // Like known(), but overrides the attribute TTL from Stat::attrs()
+    fn found(reply: Reply<'o, Self>, entry: impl Known, entry_ttl: Ttl, attr_ttl: Ttl) -> Done<'o> {
+        let (attrs, _) = entry.inode().attrs();
+        let root = reply.session.root();
+        let attrs = attrs.finish(entry.inode(), root);
+
+        let ino = entry.inode().ino().swap_root(root);
+        let done = reply.single(&make_entry((ino, entry_ttl), (attrs, attr_ttl)));
+        entry.unveil();
+
+        done
+    }
+
     fn not_found_for(reply: Reply<'o, Self>, ttl: Ttl) -> Done<'o>;
 }
 
+// READDIRPLUS requests take attributes (Stat::attrs()) along with each entry
+pub trait RequestPlus<'o>: Operation<'o> {
+    fn is_plus(request: &Request<'o, Self>) -> bool;
+}
+
 pub trait ReplyEntries<'o>: Operation<'o> {
     fn entry(reply: Reply<'o, Self>, entry: Entry<impl Known>) -> Interruptible<'o, Self, ()>;
     fn end(reply: Reply<'o, Self>) -> Done<'o>;
+
+    // Bytes left for entries, so that expensive ones can be skipped ahead of entry()
+    fn remaining(reply: &Reply<'o, Self>) -> usize;
+    fn would_fit(reply: &Reply<'o, Self>, name_len: usize) -> bool;
 }
 
 pub struct ReaddirState<B> {
     max_read: usize,
     is_plus: bool,
+    next_offset: u64,
     buffer: B,
 }
 
@@ -92,8 +115,10 @@ impl<'o> ReplyFound<'o> for Lookup {
 }
 
 impl<'o> RequestHandle<'o> for Readdir {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        readdir_read_in(request).fh
+    type Handle = DirHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        DirHandle(readdir_read_in(request).fh)
     }
 }
 
@@ -109,6 +134,12 @@ impl<'o> RequestSize<'o> for Readdir {
     }
 }
 
+impl<'o> RequestPlus<'o> for Readdir {
+    fn is_plus(request: &Request<'o, Self>) -> bool {
+        matches!(request.body, proto::OpcodeSelect::Match(_))
+    }
+}
+
 impl<'o, B> ReplyBuffered<'o, B> for Readdir
 where
     B: BufMut + AsRef<[u8]>,
@@ -121,6 +152,7 @@ where
         let ReaddirState {
             max_read,
             is_plus,
+            next_offset,
             buffer: (),
         } = reply.state;
 
@@ -130,29 +162,25 @@ where
             state: ReaddirState {
                 max_read,
                 is_plus,
+                next_offset,
                 buffer,
             },
+            guard: reply.guard,
         }
     }
 }
 
 impl<'o, B: BufMut + AsRef<[u8]>> ReplyEntries<'o> for BufferedReaddir<B> {
     fn entry(mut reply: Reply<'o, Self>, entry: Entry<impl Known>) -> Interruptible<'o, Self, ()> {
-        let entry_header_len = if reply.state.is_plus {
-            std::mem::size_of::<proto::DirentPlus>()
-        } else {
-            std::mem::size_of::<proto::Dirent>()
-        };
+        let entry_header_len = dirent_header_len(reply.state.is_plus);
 
         let name = entry.name.as_bytes();
         let padding_len = dirent_pad_bytes(entry_header_len + name.len());
 
-        let buffer = &mut reply.state.buffer;
-        let remaining = buffer
-            .remaining_mut()
-            .min(reply.state.max_read - buffer.as_ref().len());
-
+        let remaining = Self::remaining(&reply);
         let record_len = entry_header_len + name.len() + padding_len;
+
+        let buffer = &mut reply.state.buffer;
         if remaining < record_len {
             if buffer.as_ref().is_empty() {
                 log::error!("Buffer for readdir req #{} is too small", reply.unique);
@@ -173,10 +201,29 @@ impl<'o, B: BufMut + AsRef<[u8]>> ReplyEntries<'o> for BufferedReaddir<B> {
             EntryType::Socket => SFlag::S_IFSOCK,
         };
 
-        let ino = inode.ino();
+        // Zero would make the kernel restart the listing from the beginning, see Entry::new()
+        let offset = match entry.offset {
+            0 => reply.state.next_offset,
+            offset => offset,
+        };
+
+        // Offsets that go back (or don't move forward) make getdents() loop or skip entries
+        if cfg!(debug_assertions) && offset < reply.state.next_offset {
+            log::warn!(
+                "Readdir req #{} has non-increasing offset {} (expected at least {})",
+                reply.unique,
+                offset,
+                reply.state.next_offset
+            );
+        }
+
+        reply.state.next_offset = offset.saturating_add(1);
+
+        let root = reply.session.root();
+        let ino = inode.ino().swap_root(root);
         let dirent = proto::Dirent {
             ino: ino.as_raw(),
-            off: entry.offset,
+            off: offset,
             namelen: name.len().try_into().unwrap(),
             entry_type: entry_type.bits() >> 12,
         };
@@ -187,8 +234,12 @@ impl<'o, B: BufMut + AsRef<[u8]>> ReplyEntries<'o> for BufferedReaddir<B> {
         }
 
         let ent = if reply.state.is_plus {
-            let (attrs, attrs_ttl) = inode.attrs();
-            let attrs = attrs.finish(inode);
+            let (attrs, attrs_ttl) = match entry.attrs {
+                Some(attrs) => attrs,
+                None => inode.attrs(),
+            };
+
+            let attrs = attrs.finish(inode, root);
             let entry_out = make_entry((ino, entry.ttl), (attrs, attrs_ttl));
 
             if name != ".".as_bytes() && name != "..".as_bytes() {
@@ -219,18 +270,39 @@ impl<'o, B: BufMut + AsRef<[u8]>> ReplyEntries<'o> for BufferedReaddir<B> {
     fn end(reply: Reply<'o, Self>) -> Done<'o> {
         reply.inner(|reply| reply.state.buffer.as_ref())
     }
+
+    fn remaining(reply: &Reply<'o, Self>) -> usize {
+        let buffer = &reply.state.buffer;
+        let used = buffer.as_ref().len();
+
+        buffer.remaining_mut().min(reply.state.max_read - used)
+    }
+
+    fn would_fit(reply: &Reply<'o, Self>, name_len: usize) -> bool {
+        let entry_len = dirent_header_len(reply.state.is_plus) + name_len;
+        Self::remaining(reply) >= entry_len + dirent_pad_bytes(entry_len)
+    }
 }
 
 impl<'o> FromRequest<'o, Readdir> for ReaddirState<()> {
     fn from_request(request: &Request<'o, Readdir>) -> Self {
         ReaddirState {
             max_read: request.size() as usize,
-            is_plus: matches!(request.body, proto::OpcodeSelect::Match(_)),
+            is_plus: request.is_plus(),
+            next_offset: request.offset().saturating_add(1),
             buffer: (),
         }
     }
 }
 
+fn dirent_header_len(is_plus: bool) -> usize {
+    if is_plus {
+        std::mem::size_of::<proto::DirentPlus>()
+    } else {
+        std::mem::size_of::<proto::Dirent>()
+    }
+}
+
 fn dirent_pad_bytes(entry_len: usize) -> usize {
     const ALIGN_MASK: usize = (1 << proto::DIRENT_ALIGNMENT_BITS) - 1;
     ((entry_len + ALIGN_MASK) & !ALIGN_MASK) - entry_len
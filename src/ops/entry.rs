@@ -1,13 +1,13 @@
 use super::{
     c_to_os,
-    traits::{ReplyKnown, ReplyOk, RequestMode, RequestName},
+    traits::{ReplyKnown, ReplyOk, RequestFlags, RequestMode, RequestName},
 };
 
 use crate::{
-    io::{Ino, Mode},
+    io::{Ino, Mode, RenameFlags},
     proto,
     sealed::Sealed,
-    Operation, Request,
+    Done, Errno, Operation, Reply, Request,
 };
 use std::ffi::{CStr, OsStr};
 
@@ -17,6 +17,7 @@ pub enum Unlink {}
 pub enum Rmdir {}
 pub enum Symlink {}
 pub enum Link {}
+pub enum Rename {}
 
 pub trait RequestDevice<'o>: Operation<'o> {
     fn device(request: &Request<'o, Self>) -> u32;
@@ -30,12 +31,47 @@ pub trait RequestLink<'o>: Operation<'o> {
     fn source_ino(request: &Request<'o, Self>) -> Ino;
 }
 
+// The source entry is name() in the request's ino(), flags() come from rename2(2)
+pub trait RequestRename<'o>: Operation<'o> {
+    fn new_dir(request: &Request<'o, Self>) -> Ino;
+    fn new_name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr;
+}
+
+// The kernel rejects names over NAME_MAX and symlink targets over a page by itself, lower
+// limits of the filesystem (such as PATH_MAX for targets) are up to it
+pub trait ReplyNameTooLong<'o>: Operation<'o> {
+    fn name_too_long(reply: Reply<'o, Self>) -> Done<'o> {
+        reply.fail(Errno::ENAMETOOLONG)
+    }
+}
+
+pub trait ReplyRemoved<'o>: ReplyOk<'o> {
+    // The kernel holds the parent's lock until it gets the reply, so the entry can only be
+    // invalidated afterwards. A failed invalidation is logged, since the reply is already sent
+    fn ok_and_invalidate(reply: Reply<'o, Self>, parent: Ino, name: &OsStr) -> Done<'o> {
+        let session = reply.session;
+        let done = Self::ok(reply);
+
+        if let Err(errno) = session.invalidate_entry(parent, name) {
+            log::error!(
+                "Failed to invalidate entry {:?} in {}: {}",
+                name,
+                parent,
+                errno
+            );
+        }
+
+        done
+    }
+}
+
 impl Sealed for Mknod {}
 impl Sealed for Mkdir {}
 impl Sealed for Unlink {}
 impl Sealed for Rmdir {}
 impl Sealed for Symlink {}
 impl Sealed for Link {}
+impl Sealed for Rename {}
 
 impl<'o> Operation<'o> for Mknod {
     type RequestBody = (&'o proto::MknodIn, &'o CStr);
@@ -67,6 +103,21 @@ impl<'o> Operation<'o> for Link {
     type ReplyState = ();
 }
 
+impl<'o> Operation<'o> for Rename {
+    // The kernel only sends Rename2 if there are flags, see RequestFlags below
+    type RequestBody = (
+        proto::OpcodeSelect<
+            &'o proto::Rename2In,
+            &'o proto::RenameIn,
+            { proto::Opcode::Rename2 as u32 },
+        >,
+        &'o CStr, // name()
+        &'o CStr, // new_name()
+    );
+
+    type ReplyState = ();
+}
+
 impl<'o> RequestName<'o> for Mknod {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
         let (_header, name) = request.body;
@@ -94,6 +145,7 @@ impl<'o> RequestDevice<'o> for Mknod {
 }
 
 impl<'o> ReplyKnown<'o> for Mknod {}
+impl<'o> ReplyNameTooLong<'o> for Mknod {}
 
 impl<'o> RequestName<'o> for Mkdir {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
@@ -115,6 +167,7 @@ impl<'o> RequestMode<'o> for Mkdir {
 }
 
 impl<'o> ReplyKnown<'o> for Mkdir {}
+impl<'o> ReplyNameTooLong<'o> for Mkdir {}
 
 impl<'o> RequestName<'o> for Unlink {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
@@ -123,6 +176,7 @@ impl<'o> RequestName<'o> for Unlink {
 }
 
 impl<'o> ReplyOk<'o> for Unlink {}
+impl<'o> ReplyRemoved<'o> for Unlink {}
 
 impl<'o> RequestName<'o> for Rmdir {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
@@ -131,6 +185,7 @@ impl<'o> RequestName<'o> for Rmdir {
 }
 
 impl<'o> ReplyOk<'o> for Rmdir {}
+impl<'o> ReplyRemoved<'o> for Rmdir {}
 
 impl<'o> RequestName<'o> for Symlink {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
@@ -147,6 +202,7 @@ impl<'o> RequestTarget<'o> for Symlink {
 }
 
 impl<'o> ReplyKnown<'o> for Symlink {}
+impl<'o> ReplyNameTooLong<'o> for Symlink {}
 
 impl<'o> RequestName<'o> for Link {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
@@ -158,8 +214,54 @@ impl<'o> RequestName<'o> for Link {
 impl<'o> RequestLink<'o> for Link {
     fn source_ino(request: &Request<'o, Self>) -> Ino {
         let (header, _name) = request.body;
-        Ino(header.old_ino)
+        Ino(header.old_ino).swap_root(request.root)
     }
 }
 
 impl<'o> ReplyKnown<'o> for Link {}
+impl<'o> ReplyNameTooLong<'o> for Link {}
+
+impl<'o> RequestName<'o> for Rename {
+    fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
+        let (_, name, _new_name) = request.body;
+        c_to_os(name)
+    }
+}
+
+impl<'o> RequestRename<'o> for Rename {
+    fn new_dir(request: &Request<'o, Self>) -> Ino {
+        use proto::OpcodeSelect::*;
+
+        let new_dir = match request.body.0 {
+            Match(rename2) => rename2.new_dir,
+            Alt(rename) => rename.new_dir,
+        };
+
+        Ino(new_dir).swap_root(request.root)
+    }
+
+    fn new_name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
+        let (_, _name, new_name) = request.body;
+        c_to_os(new_name)
+    }
+}
+
+// NOREPLACE fails with EEXIST if new_name() exists. EXCHANGE atomically swaps both entries,
+// which must then exist. WHITEOUT is only sent by overlayfs on its upper layer: along with
+// the rename, a whiteout (a character device with device number 0) must be created at the
+// source in the same atomic step. Filesystems that don't support a flag should fail with
+// EINVAL, which the kernel forwards to the caller
+impl<'o> RequestFlags<'o> for Rename {
+    type Flags = RenameFlags;
+
+    fn flags(request: &Request<'o, Self>) -> Self::Flags {
+        use proto::OpcodeSelect::*;
+
+        match request.body.0 {
+            Match(rename2) => RenameFlags::from_bits_truncate(rename2.flags),
+            Alt(_) => RenameFlags::empty(),
+        }
+    }
+}
+
+impl<'o> ReplyOk<'o> for Rename {}
@@ -1,20 +1,75 @@
 use super::traits::ReplyOk;
-use crate::{io::FsInfo, proto, sealed::Sealed, util::page_size, Done, Operation, Reply};
+use crate::{
+    io::{FsInfo, Stat},
+    proto,
+    sealed::Sealed,
+    util::{page_size, OutputChain},
+    Done, Operation, Reply,
+};
+
+use bytemuck::bytes_of;
 
 pub enum Init {}
 pub enum Statfs {}
+pub enum Destroy {}
 
 pub struct InitState {
     pub(crate) kernel_flags: proto::InitFlags,
+    pub(crate) opt_in_flags: proto::InitFlags,
+    pub(crate) kernel_minor: u32,
     pub(crate) buffer_pages: usize,
+    pub(crate) max_write: Option<u32>,
+}
+
+pub trait ReplyInit<'o>: ReplyOk<'o> + Operation<'o, ReplyState = InitState> {
+    // Completes Init like ok(), registering `root` as the inode that the kernel knows as
+    // Ino::ROOT (see Session::root()), unless disabled by StartOptions::preserve_root_ino()
+    fn root(reply: Reply<'o, Self>, root: &impl Stat) -> Done<'o> {
+        reply.session.set_root(root.ino());
+        Self::ok(reply)
+    }
+
+    // Stateless filesystems may then reply ENOSYS to the first open (or opendir), after which
+    // the kernel stops sending them and their releases. Returns false if the kernel can't do this
+    fn no_open_support(reply: &mut Reply<'o, Self>) -> bool {
+        opt_in(reply, proto::InitFlags::NO_OPEN_SUPPOR)
+    }
+
+    fn no_opendir_support(reply: &mut Reply<'o, Self>) -> bool {
+        opt_in(reply, proto::InitFlags::NO_OPENDIR_SUPPORT)
+    }
+
+    // Advertises a smaller max_write than what request buffers could fit (see
+    // StartOptions::max_pages()), thereby bounding the size of each write request
+    fn max_write(reply: &mut Reply<'o, Self>, bytes: u32) {
+        let limit = buffer_max_write(reply.state.buffer_pages);
+        reply.state.max_write = Some(bytes.min(limit));
+    }
+
+    // The kernel then caches writes and flushes them later on, see Request<Write>::is_writeback()
+    fn writeback_cache(reply: &mut Reply<'o, Self>) -> bool {
+        opt_in(reply, proto::InitFlags::WRITEBACK_CACHE)
+    }
 }
 
 pub trait ReplyFsInfo<'o>: Operation<'o> {
     fn info(reply: Reply<'o, Self>, info: &FsInfo) -> Done<'o>;
+
+    // Keeps df(1) and statvfs(3) working for filesystems that have nothing to report
+    fn unknown(reply: Reply<'o, Self>) -> Done<'o> {
+        let block_size = page_size().try_into().unwrap();
+        let info = FsInfo::default()
+            .blocks(block_size, 0, 0, 0)
+            .fragment_size(block_size)
+            .max_filename(255);
+
+        Self::info(reply, &info)
+    }
 }
 
 impl Sealed for Init {}
 impl Sealed for Statfs {}
+impl Sealed for Destroy {}
 
 impl<'o> Operation<'o> for Init {
     type RequestBody = &'o proto::InitIn;
@@ -26,11 +81,19 @@ impl<'o> Operation<'o> for Statfs {
     type ReplyState = ();
 }
 
+impl<'o> Operation<'o> for Destroy {
+    type RequestBody = ();
+    type ReplyState = ();
+}
+
 impl<'o> ReplyOk<'o> for Init {
     fn ok(reply: Reply<'o, Self>) -> Done<'o> {
         let InitState {
             kernel_flags,
+            opt_in_flags,
+            kernel_minor,
             buffer_pages,
+            max_write,
         } = reply.state;
 
         let flags = {
@@ -39,11 +102,8 @@ impl<'o> ReplyOk<'o> for Init {
             //TODO: Conditions for these feature flags
             // - Locks
             // - ASYNC_DIO
-            // - WRITEBACK_CACHE
-            // - NO_OPEN_SUPPORT
             // - HANDLE_KILLPRIV
             // - POSIX_ACL
-            // - NO_OPENDIR_SUPPORT
             // - EXPLICIT_INVAL_DATA
 
             let supported = InitFlags::ASYNC_READ
@@ -60,32 +120,66 @@ impl<'o> ReplyOk<'o> for Init {
                 | InitFlags::MAX_PAGES
                 | InitFlags::CACHE_SYMLINKS;
 
-            kernel_flags & supported
+            kernel_flags & (supported | opt_in_flags)
         };
 
-        let buffer_size = page_size() * buffer_pages;
+        reply.session.set_active_flags(flags);
 
-        // See fs/fuse/dev.c in the kernel source tree for details about max_write
-        let max_write = buffer_size - std::mem::size_of::<(proto::InHeader, proto::WriteIn)>();
-
-        reply.single(&proto::InitOut {
+        let max_write = max_write.unwrap_or_else(|| buffer_max_write(buffer_pages));
+        let init_out = proto::InitOut {
             major: proto::MAJOR_VERSION,
             minor: proto::TARGET_MINOR_VERSION,
             max_readahead: 0, //TODO
             flags: flags.bits(),
             max_background: 0,       //TODO
             congestion_threshold: 0, //TODO
-            max_write: max_write.try_into().unwrap(),
+            max_write,
             time_gran: 1, //TODO
-            max_pages: buffer_pages.try_into().unwrap(),
+            max_pages: buffer_pages.min(proto::MAX_MAX_PAGES).try_into().unwrap(),
             padding: Default::default(),
             unused: Default::default(),
-        })
+        };
+
+        // Older kernels reject replies larger than the InitOut they know about. Flags need no
+        // such treatment, since kernel_flags only ever includes those the kernel understands
+        let init_out = bytes_of(&init_out);
+        let init_out = if kernel_minor <= proto::COMPAT_22_MINOR_VERSION {
+            &init_out[..proto::COMPAT_22_INIT_OUT_SIZE]
+        } else {
+            init_out
+        };
+
+        reply.chain(OutputChain::tail(&[init_out]))
     }
 }
 
+impl<'o> ReplyInit<'o> for Init {}
+
 impl<'o> ReplyFsInfo<'o> for Statfs {
     fn info(reply: Reply<'o, Self>, fs_info: &FsInfo) -> Done<'o> {
         reply.single(&proto::StatfsOut::from(*fs_info))
     }
 }
+
+// The kernel waits for this reply before completing the unmount
+impl<'o> ReplyOk<'o> for Destroy {}
+
+fn opt_in<'o, O>(reply: &mut Reply<'o, O>, flag: proto::InitFlags) -> bool
+where
+    O: Operation<'o, ReplyState = InitState>,
+{
+    let supported = reply.state.kernel_flags.contains(flag);
+    if supported {
+        reply.state.opt_in_flags |= flag;
+    }
+
+    supported
+}
+
+// See fs/fuse/dev.c in the kernel source tree for details about max_write
+pub(crate) fn buffer_max_write(buffer_pages: usize) -> u32 {
+    let buffer_size = page_size() * buffer_pages;
+    let max_write = buffer_size - std::mem::size_of::<(proto::InHeader, proto::WriteIn)>();
+
+    max_write.try_into().unwrap()
+}
@@ -1,6 +1,6 @@
-use super::traits::{ReplyOk, RequestHandle};
+use super::traits::{ReplyOk, RequestHandle, RequestLockOwner};
 use crate::{
-    io::{Ino, Stat},
+    io::{FileHandle, Gid, Ino, Mode, RawAttrs, SetTime, SetattrValid, Stat, Timestamp, Ttl, Uid},
     proto,
     sealed::Sealed,
     Done, Operation, Reply, Request,
@@ -8,6 +8,7 @@ use crate::{
 
 pub enum Forget {}
 pub enum Getattr {}
+pub enum Setattr {}
 pub enum Bmap {}
 
 pub trait RequestForget<'o>: Operation<'o> {
@@ -19,8 +20,47 @@ pub trait RequestBlock<'o>: Operation<'o> {
     fn block_size(request: &Request<'o, Self>) -> u32;
 }
 
+// Only attributes in valid() are to be changed, the new_*() accessors return None for the rest.
+// Changes other than to times also bump ctime. Note that the reply is a full stat() of the
+// inode after the changes, not just of the changed attributes
+pub trait RequestSetattr<'o>: Operation<'o> {
+    fn valid(request: &Request<'o, Self>) -> SetattrValid;
+    fn new_mode(request: &Request<'o, Self>) -> Option<Mode>;
+    fn new_owner(request: &Request<'o, Self>) -> (Option<Uid>, Option<Gid>);
+
+    // Truncation or extension, as by truncate(2)
+    fn new_size(request: &Request<'o, Self>) -> Option<u64>;
+
+    fn new_atime(request: &Request<'o, Self>) -> Option<SetTime>;
+    fn new_mtime(request: &Request<'o, Self>) -> Option<SetTime>;
+
+    // Only sent along with writeback caching, see Reply<Init>::writeback_cache()
+    fn new_ctime(request: &Request<'o, Self>) -> Option<Timestamp>;
+}
+
 pub trait ReplyStat<'o>: Operation<'o> {
-    fn stat(reply: Reply<'o, Self>, inode: &impl Stat) -> Done<'o>;
+    fn stat(reply: Reply<'o, Self>, inode: &impl Stat) -> Done<'o> {
+        let (attrs, ttl) = inode.attrs();
+        let attrs = attrs.finish(inode, reply.session.root());
+        reply_attrs(reply, attrs, ttl)
+    }
+
+    fn stat_with_ttl(reply: Reply<'o, Self>, inode: &impl Stat, ttl: Ttl) -> Done<'o> {
+        let (attrs, _) = inode.attrs();
+        let attrs = attrs.finish(inode, reply.session.root());
+        reply_attrs(reply, attrs, ttl)
+    }
+
+    fn raw_attrs(reply: Reply<'o, Self>, attrs: &RawAttrs, ttl: Ttl) -> Done<'o> {
+        let attrs = attrs.finish(reply.session.root());
+        reply_attrs(reply, attrs, ttl)
+    }
+
+    // Attributes that must never be cached, for metadata that changes behind the kernel's back.
+    // The kernel then asks again on every stat(2) instead of trusting what it saw before
+    fn stat_fresh(reply: Reply<'o, Self>, inode: &impl Stat) -> Done<'o> {
+        Self::stat_with_ttl(reply, inode, Ttl::NULL)
+    }
 }
 
 pub trait ReplyBlock<'o>: Operation<'o> {
@@ -29,11 +69,12 @@ pub trait ReplyBlock<'o>: Operation<'o> {
 
 pub enum ForgetList<'a> {
     Single(Option<(Ino, u64)>),
-    Batch(std::slice::Iter<'a, proto::ForgetOne>),
+    Batch(std::slice::Iter<'a, proto::ForgetOne>, Ino),
 }
 
 impl Sealed for Forget {}
 impl Sealed for Getattr {}
+impl Sealed for Setattr {}
 impl Sealed for Bmap {}
 
 impl<'o> Operation<'o> for Forget {
@@ -51,6 +92,11 @@ impl<'o> Operation<'o> for Getattr {
     type ReplyState = ();
 }
 
+impl<'o> Operation<'o> for Setattr {
+    type RequestBody = &'o proto::SetattrIn;
+    type ReplyState = ();
+}
+
 impl<'o> Operation<'o> for Bmap {
     type RequestBody = &'o proto::BmapIn;
     type ReplyState = ();
@@ -60,27 +106,29 @@ impl<'o> RequestForget<'o> for Forget {
     fn forget_list<'a>(request: &'a Request<'o, Self>) -> ForgetList<'a> {
         use {proto::OpcodeSelect::*, ForgetList::*};
 
-        impl Iterator for ForgetList<'_> {
-            type Item = (Ino, u64);
-
-            fn next(&mut self) -> Option<Self::Item> {
-                match self {
-                    Single(single) => single.take(),
-                    Batch(batch) => {
-                        let forget = batch.next()?;
-                        Some((Ino(forget.ino), forget.nlookup))
-                    }
-                }
-            }
-        }
-
         match request.body {
-            Match((_, slice)) => Batch(slice.iter()),
+            Match((_, slice)) => Batch(slice.iter(), request.root),
             Alt(single) => Single(Some((request.ino(), single.nlookup))),
         }
     }
 }
 
+impl Iterator for ForgetList<'_> {
+    type Item = (Ino, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        use ForgetList::*;
+
+        match self {
+            Single(single) => single.take(),
+            Batch(batch, root) => {
+                let forget = batch.next()?;
+                Some((Ino(forget.ino).swap_root(*root), forget.nlookup))
+            }
+        }
+    }
+}
+
 impl<'o> ReplyOk<'o> for Forget {
     fn ok(_reply: Reply<'o, Self>) -> Done<'o> {
         // No reply for forget requests
@@ -88,26 +136,99 @@ impl<'o> ReplyOk<'o> for Forget {
     }
 }
 
+// Present if the caller holds an open file, as in fstat(2). Otherwise fh is meaningless
 impl<'o> RequestHandle<'o> for Getattr {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.fh
+    type Handle = Option<FileHandle>;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        let getattr = request.body;
+        proto::GetattrFlags::from_bits_truncate(getattr.flags)
+            .contains(proto::GetattrFlags::FH)
+            .then_some(FileHandle(getattr.fh))
     }
 }
 
-impl<'o> ReplyStat<'o> for Getattr {
-    fn stat(reply: Reply<'o, Self>, inode: &impl Stat) -> Done<'o> {
-        let (attrs, ttl) = inode.attrs();
-        let attrs = attrs.finish(inode);
+impl<'o> ReplyStat<'o> for Getattr {}
+
+// Present if the change comes through an open file, such as by ftruncate(2)
+impl<'o> RequestHandle<'o> for Setattr {
+    type Handle = Option<FileHandle>;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        let setattr = request.body;
+        Self::valid(request)
+            .contains(SetattrValid::FH)
+            .then_some(FileHandle(setattr.fh))
+    }
+}
 
-        reply.single(&proto::AttrOut {
-            attr_valid: ttl.seconds(),
-            attr_valid_nsec: ttl.nanoseconds(),
-            dummy: Default::default(),
-            attr: attrs,
-        })
+impl<'o> RequestLockOwner<'o> for Setattr {
+    fn lock_owner(request: &Request<'o, Self>) -> Option<u64> {
+        let setattr = request.body;
+        Self::valid(request)
+            .contains(SetattrValid::LOCKOWNER)
+            .then_some(setattr.lock_owner)
     }
 }
 
+impl<'o> RequestSetattr<'o> for Setattr {
+    fn valid(request: &Request<'o, Self>) -> SetattrValid {
+        SetattrValid::from_bits_truncate(request.body.valid)
+    }
+
+    fn new_mode(request: &Request<'o, Self>) -> Option<Mode> {
+        let setattr = request.body;
+        Self::valid(request)
+            .contains(SetattrValid::MODE)
+            .then(|| Mode::from_bits_truncate(setattr.mode))
+    }
+
+    fn new_owner(request: &Request<'o, Self>) -> (Option<Uid>, Option<Gid>) {
+        let (setattr, valid) = (request.body, Self::valid(request));
+        let uid = valid.contains(SetattrValid::UID);
+        let gid = valid.contains(SetattrValid::GID);
+
+        (
+            uid.then(|| Uid::from_raw(setattr.uid)),
+            gid.then(|| Gid::from_raw(setattr.gid)),
+        )
+    }
+
+    fn new_size(request: &Request<'o, Self>) -> Option<u64> {
+        let setattr = request.body;
+        Self::valid(request)
+            .contains(SetattrValid::SIZE)
+            .then_some(setattr.size)
+    }
+
+    fn new_atime(request: &Request<'o, Self>) -> Option<SetTime> {
+        let setattr = request.body;
+        set_time(
+            Self::valid(request),
+            (SetattrValid::ATIME, SetattrValid::ATIME_NOW),
+            (setattr.atime, setattr.atimensec),
+        )
+    }
+
+    fn new_mtime(request: &Request<'o, Self>) -> Option<SetTime> {
+        let setattr = request.body;
+        set_time(
+            Self::valid(request),
+            (SetattrValid::MTIME, SetattrValid::MTIME_NOW),
+            (setattr.mtime, setattr.mtimensec),
+        )
+    }
+
+    fn new_ctime(request: &Request<'o, Self>) -> Option<Timestamp> {
+        let setattr = request.body;
+        Self::valid(request)
+            .contains(SetattrValid::CTIME)
+            .then(|| Timestamp::new(setattr.ctime as i64, setattr.ctimensec))
+    }
+}
+
+impl<'o> ReplyStat<'o> for Setattr {}
+
 impl<'o> RequestBlock<'o> for Bmap {
     fn block(request: &Request<'o, Self>) -> u64 {
         request.body.block
@@ -123,3 +244,58 @@ impl<'o> ReplyBlock<'o> for Bmap {
         reply.single(&proto::BmapOut { block })
     }
 }
+
+// The kernel sets both flags for UTIME_NOW (see iattr_to_fattr() in fs/fuse/dir.c)
+fn set_time(
+    valid: SetattrValid,
+    (at, now): (SetattrValid, SetattrValid),
+    (seconds, nanoseconds): (u64, u32),
+) -> Option<SetTime> {
+    if valid.contains(now) {
+        Some(SetTime::Now)
+    } else if valid.contains(at) {
+        Some(SetTime::At(Timestamp::new(seconds as i64, nanoseconds)))
+    } else {
+        None
+    }
+}
+
+fn reply_attrs<'o, O: Operation<'o>>(
+    reply: Reply<'o, O>,
+    attrs: proto::Attrs,
+    ttl: Ttl,
+) -> Done<'o> {
+    reply.single(&attr_out(attrs, ttl))
+}
+
+fn attr_out(attrs: proto::Attrs, ttl: Ttl) -> proto::AttrOut {
+    proto::AttrOut {
+        attr_valid: ttl.seconds(),
+        attr_valid_nsec: ttl.nanoseconds(),
+        dummy: Default::default(),
+        attr: attrs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_time_now_wins_over_explicit() {
+        let pair = (SetattrValid::ATIME, SetattrValid::ATIME_NOW);
+        let both = SetattrValid::ATIME | SetattrValid::ATIME_NOW;
+
+        assert!(matches!(set_time(both, pair, (5, 6)), Some(SetTime::Now)));
+        assert!(set_time(SetattrValid::MTIME, pair, (5, 6)).is_none());
+
+        let explicit = set_time(SetattrValid::ATIME, pair, (0, 0));
+        assert!(explicit == Some(SetTime::At(Timestamp::new(0, 0))));
+    }
+
+    #[test]
+    fn null_ttl_is_never_cached() {
+        let out = attr_out(bytemuck::Zeroable::zeroed(), Ttl::NULL);
+        assert_eq!((out.attr_valid, out.attr_valid_nsec), (0, 0));
+    }
+}
@@ -0,0 +1,142 @@
+use super::{
+    traits::{RequestData, RequestFlags, RequestHandle, RequestSize},
+    FromRequest,
+};
+
+use crate::{
+    io::{FileHandle, IoctlFlags},
+    proto,
+    sealed::Sealed,
+    util::OutputChain,
+    Done, Operation, Reply, Request,
+};
+use bytemuck::{bytes_of, cast_slice};
+
+pub enum Ioctl {}
+
+pub struct IoctlState {
+    flags: IoctlFlags,
+    out_size: u32,
+}
+
+pub trait RequestIoctl<'o>: Operation<'o> {
+    fn command(request: &Request<'o, Self>) -> u32;
+    fn argument(request: &Request<'o, Self>) -> u64;
+}
+
+pub trait ReplyIoctl<'o>: Operation<'o> {
+    fn ioctl(reply: Reply<'o, Self>, result: i32, output: &[u8]) -> Done<'o>;
+
+    // Each range is (address, length) in the caller's address space
+    fn retry_ioctl(reply: Reply<'o, Self>, input: &[(u64, u64)], output: &[(u64, u64)])
+        -> Done<'o>;
+}
+
+impl Sealed for Ioctl {}
+
+impl<'o> Operation<'o> for Ioctl {
+    type RequestBody = (&'o proto::IoctlIn, &'o [u8]);
+    type ReplyState = IoctlState;
+}
+
+impl<'o> RequestHandle<'o> for Ioctl {
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.0.fh)
+    }
+}
+
+impl<'o> RequestFlags<'o> for Ioctl {
+    type Flags = IoctlFlags;
+
+    fn flags(request: &Request<'o, Self>) -> Self::Flags {
+        IoctlFlags::from_bits_truncate(request.body.0.flags)
+    }
+}
+
+impl<'o> RequestIoctl<'o> for Ioctl {
+    fn command(request: &Request<'o, Self>) -> u32 {
+        request.body.0.cmd
+    }
+
+    fn argument(request: &Request<'o, Self>) -> u64 {
+        request.body.0.arg
+    }
+}
+
+// Maximum length of the output that the caller expects back
+impl<'o> RequestSize<'o> for Ioctl {
+    fn size(request: &Request<'o, Self>) -> u32 {
+        request.body.0.out_size
+    }
+}
+
+impl<'o> RequestData<'o> for Ioctl {
+    fn data<'a>(request: &'a Request<'o, Self>) -> &'a [u8] {
+        request.body.1
+    }
+}
+
+impl<'o> ReplyIoctl<'o> for Ioctl {
+    fn ioctl(reply: Reply<'o, Self>, result: i32, output: &[u8]) -> Done<'o> {
+        if output.len() > reply.state.out_size as usize {
+            log::error!(
+                "Ioctl output for req #{} exceeds the requested size ({} > {})",
+                reply.unique,
+                output.len(),
+                reply.state.out_size
+            );
+
+            return reply.io_error();
+        }
+
+        let ioctl_out = proto::IoctlOut {
+            result,
+            flags: 0,
+            in_iovs: 0,
+            out_iovs: 0,
+        };
+
+        reply.chain(OutputChain::tail(&[bytes_of(&ioctl_out), output]))
+    }
+
+    fn retry_ioctl(
+        reply: Reply<'o, Self>,
+        input: &[(u64, u64)],
+        output: &[(u64, u64)],
+    ) -> Done<'o> {
+        // The kernel only accepts retries for unrestricted ioctls (see CuseDevice)
+        if !reply.state.flags.contains(IoctlFlags::UNRESTRICTED) {
+            log::error!("Attempted to retry restricted ioctl req #{}", reply.unique);
+            return reply.io_error();
+        }
+
+        let iovec = |&(base, len): &(u64, u64)| proto::IoctlIovec { base, len };
+        let input: Vec<_> = input.iter().map(iovec).collect();
+        let output: Vec<_> = output.iter().map(iovec).collect();
+
+        let ioctl_out = proto::IoctlOut {
+            result: 0,
+            flags: IoctlFlags::RETRY.bits(),
+            in_iovs: input.len().try_into().unwrap(),
+            out_iovs: output.len().try_into().unwrap(),
+        };
+
+        let tail = [
+            bytes_of(&ioctl_out),
+            cast_slice(&input),
+            cast_slice(&output),
+        ];
+        reply.chain(OutputChain::tail(&tail))
+    }
+}
+
+impl<'o> FromRequest<'o, Ioctl> for IoctlState {
+    fn from_request(request: &Request<'o, Ioctl>) -> Self {
+        IoctlState {
+            flags: request.flags(),
+            out_size: request.body.0.out_size,
+        }
+    }
+}
@@ -16,17 +16,21 @@ use bytemuck::{bytes_of, Pod};
 pub mod traits;
 
 pub use dir::{BufferedReaddir, Lookup, Readdir};
-pub use entry::{Link, Mkdir, Mknod, Rmdir, Symlink, Unlink};
-pub use global::{Init, Statfs};
-pub use inode::{Bmap, Forget, Getattr};
+pub use entry::{Link, Mkdir, Mknod, Rename, Rmdir, Symlink, Unlink};
+pub use global::{Destroy, Init, Statfs};
+pub use inode::{Bmap, Forget, Getattr, Setattr};
+pub use ioctl::Ioctl;
 pub use open::{Access, Create, Open, Opendir, Release, Releasedir};
-pub use rw::{Flush, Fsync, Fsyncdir, Read, Readlink, Write};
+pub use rw::{Flush, Fsync, Fsyncdir, Poll, Read, Readlink, Write};
 pub use xattr::{Getxattr, Listxattr, Removexattr, Setxattr};
 
+pub(crate) use global::buffer_max_write;
+
 mod dir;
 mod entry;
 mod global;
 mod inode;
+mod ioctl;
 mod open;
 mod rw;
 mod xattr;
@@ -1,6 +1,6 @@
 use crate::{
-    io::{AccessFlags, Known, Mode, OpenFlags, Stat, Ttl},
-    proto::{self, OpenOutFlags},
+    io::{AccessFlags, DirHandle, FileHandle, Known, Mode, OpenFlags, Stat, Ttl},
+    proto::{self, OpenOutFlags, ReleaseFlags},
     sealed::Sealed,
     util::OutputChain,
     Done, Errno, Operation, Reply, Request,
@@ -8,7 +8,10 @@ use crate::{
 
 use super::{
     c_to_os, make_entry,
-    traits::{ReplyKnown, ReplyOk, RequestFlags, RequestHandle, RequestMode, RequestName},
+    traits::{
+        ReplyKnown, ReplyNameTooLong, ReplyOk, RequestFlags, RequestHandle, RequestLockOwner,
+        RequestMode, RequestName,
+    },
     FromRequest,
 };
 
@@ -23,35 +26,54 @@ pub enum Access {}
 pub enum Create {}
 
 pub trait ReplyOpen<'o>: Operation<'o, ReplyState = OpenOutFlags> {
-    fn ok_with_handle(reply: Reply<'o, Self>, handle: u64) -> Done<'o>
+    type Handle: Copy + Into<u64>;
+
+    fn ok_with_handle(reply: Reply<'o, Self>, handle: Self::Handle) -> Done<'o>
     where
         Self: ReplyOk<'o>,
     {
-        let open_flags = open_flags_bits(reply.state);
+        let open_flags = open_flags_bits(&reply);
 
         reply.single(&proto::OpenOut {
-            fh: handle,
+            fh: handle.into(),
             open_flags,
             padding: Default::default(),
         })
     }
 
+    fn ok_for(mut reply: Reply<'o, Self>, inode: &impl Stat, handle: Self::Handle) -> Done<'o>
+    where
+        Self: ReplyOk<'o>,
+    {
+        if inode.direct_io() {
+            Self::force_direct_io(&mut reply);
+        }
+
+        Self::ok_with_handle(reply, handle)
+    }
+
     fn known_with_handle(
-        reply: Reply<'o, Self>,
+        mut reply: Reply<'o, Self>,
         known: impl Known,
         ttl: Ttl,
-        handle: u64,
+        handle: Self::Handle,
     ) -> Done<'o>
     where
         Self: ReplyKnown<'o>,
     {
+        if known.inode().direct_io() {
+            Self::force_direct_io(&mut reply);
+        }
+
         let (attrs, attrs_ttl) = known.inode().attrs();
-        let attrs = attrs.finish(known.inode());
+        let root = reply.session.root();
+        let attrs = attrs.finish(known.inode(), root);
 
-        let entry = make_entry((known.inode().ino(), ttl), (attrs, attrs_ttl));
+        let ino = known.inode().ino().swap_root(root);
+        let entry = make_entry((ino, ttl), (attrs, attrs_ttl));
         let open = proto::OpenOut {
-            fh: handle,
-            open_flags: open_flags_bits(reply.state),
+            fh: handle.into(),
+            open_flags: open_flags_bits(&reply),
             padding: Default::default(),
         };
 
@@ -65,6 +87,14 @@ pub trait ReplyOpen<'o>: Operation<'o, ReplyState = OpenOutFlags> {
         reply.state |= OpenOutFlags::DIRECT_IO;
     }
 
+    fn keep_cache(reply: &mut Reply<'o, Self>) {
+        reply.state |= OpenOutFlags::KEEP_CACHE;
+    }
+
+    fn cache_dir(reply: &mut Reply<'o, Self>) {
+        reply.state |= OpenOutFlags::CACHE_DIR;
+    }
+
     fn non_seekable(reply: &mut Reply<'o, Self>) {
         reply.state |= OpenOutFlags::NONSEEKABLE;
     }
@@ -74,6 +104,17 @@ pub trait ReplyOpen<'o>: Operation<'o, ReplyState = OpenOutFlags> {
     }
 }
 
+pub trait RequestOpenMode<'o>: RequestFlags<'o, Flags = OpenFlags> {
+    // ATOMIC_O_TRUNC is negotiated, so the kernel won't follow up with a truncating setattr
+    fn truncate(request: &Request<'o, Self>) -> bool {
+        Self::flags(request).contains(OpenFlags::O_TRUNC)
+    }
+
+    fn append(request: &Request<'o, Self>) -> bool {
+        Self::flags(request).contains(OpenFlags::O_APPEND)
+    }
+}
+
 pub trait ReplyPermissionDenied<'o>: Operation<'o> {
     fn permission_denied(reply: Reply<'o, Self>) -> Done<'o> {
         reply.fail(Errno::EACCES)
@@ -125,35 +166,65 @@ impl<'o> RequestFlags<'o> for Open {
     }
 }
 
+impl<'o> RequestOpenMode<'o> for Open {}
+
 impl<'o> ReplyOk<'o> for Open {
     fn ok(reply: Reply<'o, Self>) -> Done<'o> {
-        reply.ok_with_handle(0)
+        reply.ok_with_handle(FileHandle(0))
     }
 }
 
-impl<'o> ReplyOpen<'o> for Open {}
+impl<'o> ReplyOpen<'o> for Open {
+    type Handle = FileHandle;
+}
 impl<'o> ReplyPermissionDenied<'o> for Open {}
 
 impl<'o> RequestHandle<'o> for Release {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.fh
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.fh)
+    }
+}
+
+impl<'o> RequestLockOwner<'o> for Release {
+    fn lock_owner(request: &Request<'o, Self>) -> Option<u64> {
+        let flags = ReleaseFlags::from_bits_truncate(request.body.release_flags);
+
+        flags
+            .contains(ReleaseFlags::FLOCK_UNLOCK)
+            .then_some(request.body.lock_owner)
     }
 }
 
 impl<'o> ReplyOk<'o> for Release {}
 
+// The kernel strips O_CREAT, O_EXCL and O_NOCTTY before forwarding, but keeps the likes of
+// O_NOATIME and O_DIRECTORY
+impl<'o> RequestFlags<'o> for Opendir {
+    type Flags = OpenFlags;
+
+    fn flags(request: &Request<'o, Self>) -> Self::Flags {
+        OpenFlags::from_bits_truncate(request.body.open_in.flags as _)
+    }
+}
+
 impl<'o> ReplyOk<'o> for Opendir {
     fn ok(reply: Reply<'o, Self>) -> Done<'o> {
-        reply.ok_with_handle(0)
+        reply.ok_with_handle(DirHandle(0))
     }
 }
 
 impl<'o> ReplyPermissionDenied<'o> for Opendir {}
-impl<'o> ReplyOpen<'o> for Opendir {}
+impl<'o> ReplyOpen<'o> for Opendir {
+    type Handle = DirHandle;
+}
 
 impl<'o> RequestHandle<'o> for Releasedir {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.release_in.fh
+    type Handle = DirHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        DirHandle(request.body.release_in.fh)
     }
 }
 
@@ -205,15 +276,32 @@ impl<'o> RequestFlags<'o> for Create {
     }
 }
 
+impl<'o> RequestOpenMode<'o> for Create {}
+
 impl<'o> ReplyKnown<'o> for Create {
     fn known(reply: Reply<'o, Self>, entry: impl Known, ttl: Ttl) -> Done<'o> {
-        reply.known_with_handle(entry, ttl, 0)
+        reply.known_with_handle(entry, ttl, FileHandle(0))
     }
 }
 
-impl<'o> ReplyOpen<'o> for Create {}
+impl<'o> ReplyOpen<'o> for Create {
+    type Handle = FileHandle;
+}
 impl<'o> ReplyPermissionDenied<'o> for Create {}
+impl<'o> ReplyNameTooLong<'o> for Create {}
+
+fn open_flags_bits<'o, O: ReplyOpen<'o>>(reply: &Reply<'o, O>) -> u32 {
+    let mut flags = reply.state;
+
+    // Direct I/O bypasses the page cache, so there's nothing to keep
+    if flags.contains(OpenOutFlags::DIRECT_IO | OpenOutFlags::KEEP_CACHE) {
+        log::warn!(
+            "Open reply #{} requests both DIRECT_IO and KEEP_CACHE, ignoring KEEP_CACHE",
+            reply.unique
+        );
+
+        flags.remove(OpenOutFlags::KEEP_CACHE);
+    }
 
-fn open_flags_bits(flags: OpenOutFlags) -> u32 {
-    (flags & OpenOutFlags::KEEP_CACHE & OpenOutFlags::CACHE_DIR).bits()
+    flags.bits()
 }
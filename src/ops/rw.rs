@@ -1,11 +1,23 @@
+use smallvec::SmallVec;
+use std::{ops::Range, os::unix::io::RawFd};
+
 use super::{
     traits::{
-        ReplyGather, ReplyOk, RequestData, RequestFlags, RequestHandle, RequestOffset, RequestSize,
+        ReplyGather, ReplyOk, RequestData, RequestFlags, RequestHandle, RequestLockOwner,
+        RequestOffset, RequestSize,
     },
     FromRequest,
 };
 
-use crate::{io::FsyncFlags, proto, sealed::Sealed, Done, Operation, Reply, Request};
+use crate::{
+    io::{
+        DirHandle, FileHandle, FsyncFlags, OpenFlags, PollFlags, ReadFlags, Timestamp, WriteFlags,
+    },
+    proto,
+    sealed::Sealed,
+    util::OutputChain,
+    Done, Operation, Reply, Request,
+};
 
 pub enum Readlink {}
 pub enum Read {}
@@ -13,21 +25,79 @@ pub enum Write {}
 pub enum Fsync {}
 pub enum Flush {}
 pub enum Fsyncdir {}
+pub enum Poll {}
+
+pub struct ReadState {
+    size: u32,
+}
 
 pub struct WriteState {
     size: u32,
 }
 
+pub trait RequestWriteback<'o>: Operation<'o> {
+    fn is_writeback(request: &Request<'o, Self>) -> bool;
+}
+
+// What a successful write should set mtime and ctime to, otherwise tools such as make(1) won't
+// notice the change. None for writeback writes, since the kernel then keeps the times itself and
+// sends them later through setattr. Replying doesn't touch the kernel's cached attributes
+pub trait RequestModified<'o>: Operation<'o> {
+    fn modified(request: &Request<'o, Self>) -> Option<Timestamp>;
+}
+
+// Flags of the open file, as of the read or write. O_DIRECT I/O (unlike the page cache) may
+// reach past the size that the kernel last knew of, so it must not be clamped to a size that was
+// cached when opening: a file may have grown since. The same goes for handles that were opened
+// with direct I/O by the filesystem (see Stat::direct_io()), which it must remember by itself
+pub trait RequestOpenFlags<'o>: Operation<'o> {
+    fn open_flags(request: &Request<'o, Self>) -> OpenFlags;
+
+    fn is_direct(request: &Request<'o, Self>) -> bool {
+        Self::open_flags(request).contains(OpenFlags::O_DIRECT)
+    }
+}
+
+// None if the end of the range would overflow, which only a broken or malicious client sends
+pub trait RequestRange<'o>: Operation<'o> {
+    fn range(request: &Request<'o, Self>) -> Option<Range<u64>>;
+}
+
+// Replies with data straight from a file descriptor, such as for passthrough filesystems. The
+// data is spliced through a pipe if the kernel allows it and read with pread(2) otherwise
+pub trait ReplySplice<'o>: Operation<'o> {
+    fn splice_from(reply: Reply<'o, Self>, fd: RawFd, offset: u64, len: usize) -> Done<'o>;
+}
+
+pub trait ReplyEof<'o>: Operation<'o> {
+    // Reads at or past EOF must be answered with short or empty data, never with an error
+    fn eof(reply: Reply<'o, Self>) -> Done<'o> {
+        reply.empty()
+    }
+}
+
 pub trait ReplyAll<'o>: Operation<'o> {
     fn all(reply: Reply<'o, Self>) -> Done<'o>;
 }
 
+pub trait RequestPoll<'o>: Operation<'o> {
+    fn events(request: &Request<'o, Self>) -> PollFlags;
+
+    // Set if the kernel wants a Session::poll_wakeup() once the handle becomes ready
+    fn wakeup_handle(request: &Request<'o, Self>) -> Option<u64>;
+}
+
+pub trait ReplyPoll<'o>: Operation<'o> {
+    fn ready(reply: Reply<'o, Self>, events: PollFlags) -> Done<'o>;
+}
+
 impl Sealed for Readlink {}
 impl Sealed for Read {}
 impl Sealed for Write {}
 impl Sealed for Fsync {}
 impl Sealed for Flush {}
 impl Sealed for Fsyncdir {}
+impl Sealed for Poll {}
 
 impl<'o> Operation<'o> for Readlink {
     type RequestBody = ();
@@ -36,7 +106,7 @@ impl<'o> Operation<'o> for Readlink {
 
 impl<'o> Operation<'o> for Read {
     type RequestBody = &'o proto::ReadIn;
-    type ReplyState = ();
+    type ReplyState = ReadState;
 }
 
 impl<'o> Operation<'o> for Write {
@@ -59,11 +129,37 @@ impl<'o> Operation<'o> for Fsyncdir {
     type ReplyState = ();
 }
 
-impl<'o> ReplyGather<'o> for Readlink {}
+impl<'o> Operation<'o> for Poll {
+    type RequestBody = &'o proto::PollIn;
+    type ReplyState = ();
+}
+
+impl<'o> ReplyGather<'o> for Readlink {
+    // Symlink targets may not contain NULs and are limited to PATH_MAX bytes (including NUL)
+    fn gather(reply: Reply<'o, Self>, fragments: &[&[u8]]) -> Done<'o> {
+        let length: usize = fragments.iter().map(|fragment| fragment.len()).sum();
+        let has_nul = fragments.iter().any(|fragment| fragment.contains(&b'\0'));
+
+        if has_nul || length >= nix::libc::PATH_MAX as usize {
+            log::error!(
+                "Bad symlink target for readlink req #{} (length={}, has_nul={})",
+                reply.unique,
+                length,
+                has_nul
+            );
+
+            return reply.io_error();
+        }
+
+        reply.chain(OutputChain::tail(fragments))
+    }
+}
 
 impl<'o> RequestHandle<'o> for Read {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.fh
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.fh)
     }
 }
 
@@ -79,11 +175,88 @@ impl<'o> RequestSize<'o> for Read {
     }
 }
 
-impl<'o> ReplyGather<'o> for Read {}
+impl<'o> RequestRange<'o> for Read {
+    fn range(request: &Request<'o, Self>) -> Option<Range<u64>> {
+        let start = request.body.offset;
+        let end = start.checked_add(request.body.size.into())?;
+
+        Some(start..end)
+    }
+}
+
+// LOCKOWNER is already accounted for by lock_owner()
+impl<'o> RequestFlags<'o> for Read {
+    type Flags = ReadFlags;
+
+    fn flags(request: &Request<'o, Self>) -> Self::Flags {
+        ReadFlags::from_bits_truncate(request.body.read_flags)
+    }
+}
+
+impl<'o> RequestOpenFlags<'o> for Read {
+    fn open_flags(request: &Request<'o, Self>) -> OpenFlags {
+        OpenFlags::from_bits_truncate(request.body.flags as _)
+    }
+}
+
+impl<'o> ReplySplice<'o> for Read {
+    fn splice_from(reply: Reply<'o, Self>, fd: RawFd, offset: u64, len: usize) -> Done<'o> {
+        let len = len.min(reply.state.size as usize);
+        let result = reply.session.splice_ok(reply.unique, fd, offset, len);
+        reply.finish(result)
+    }
+}
+
+impl<'o> ReplyEof<'o> for Read {}
+
+impl<'o> RequestLockOwner<'o> for Read {
+    fn lock_owner(request: &Request<'o, Self>) -> Option<u64> {
+        let flags = ReadFlags::from_bits_truncate(request.body.read_flags);
+
+        flags
+            .contains(ReadFlags::LOCKOWNER)
+            .then_some(request.body.lock_owner)
+    }
+}
+
+// The kernel rejects replies longer than the requested size, failing the whole read. Excess
+// data, such as the rest of a block that was read in full, is logged and cut off
+impl<'o> ReplyGather<'o> for Read {
+    fn gather(reply: Reply<'o, Self>, fragments: &[&[u8]]) -> Done<'o> {
+        let length: usize = fragments.iter().map(|fragment| fragment.len()).sum();
+        let max = reply.state.size as usize;
+
+        if length <= max {
+            return reply.chain(OutputChain::tail(fragments));
+        }
+
+        log::warn!(
+            "Read reply for req #{} is {} bytes long, truncating to the requested {}",
+            reply.unique,
+            length,
+            max
+        );
+
+        let mut remaining = max;
+        let truncated: SmallVec<[&[u8]; 8]> = fragments
+            .iter()
+            .map(|fragment| {
+                let fragment = &fragment[..fragment.len().min(remaining)];
+                remaining -= fragment.len();
+                fragment
+            })
+            .filter(|fragment| !fragment.is_empty())
+            .collect();
+
+        reply.chain(OutputChain::tail(&truncated))
+    }
+}
 
 impl<'o> RequestHandle<'o> for Write {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.0.fh
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.0.fh)
     }
 }
 
@@ -93,6 +266,55 @@ impl<'o> RequestOffset<'o> for Write {
     }
 }
 
+impl<'o> RequestRange<'o> for Write {
+    fn range(request: &Request<'o, Self>) -> Option<Range<u64>> {
+        let (header, data) = request.body;
+        let start = header.offset;
+        let end = start.checked_add(data.len().try_into().ok()?)?;
+
+        Some(start..end)
+    }
+}
+
+impl<'o> RequestFlags<'o> for Write {
+    type Flags = WriteFlags;
+
+    fn flags(request: &Request<'o, Self>) -> Self::Flags {
+        WriteFlags::from_bits_truncate(request.body.0.write_flags)
+    }
+}
+
+// Writeback writes (see Reply<Init>::writeback_cache()) may carry an invalid handle
+impl<'o> RequestWriteback<'o> for Write {
+    fn is_writeback(request: &Request<'o, Self>) -> bool {
+        WriteFlags::from_bits_truncate(request.body.0.write_flags).contains(WriteFlags::CACHE)
+    }
+}
+
+impl<'o> RequestOpenFlags<'o> for Write {
+    fn open_flags(request: &Request<'o, Self>) -> OpenFlags {
+        let (header, _data) = request.body;
+        OpenFlags::from_bits_truncate(header.flags as _)
+    }
+}
+
+impl<'o> RequestModified<'o> for Write {
+    fn modified(request: &Request<'o, Self>) -> Option<Timestamp> {
+        (!request.is_writeback()).then(Timestamp::now)
+    }
+}
+
+impl<'o> RequestLockOwner<'o> for Write {
+    fn lock_owner(request: &Request<'o, Self>) -> Option<u64> {
+        let (header, _data) = request.body;
+        let flags = WriteFlags::from_bits_truncate(header.write_flags);
+
+        flags
+            .contains(WriteFlags::LOCKOWNER)
+            .then_some(header.lock_owner)
+    }
+}
+
 impl<'o> RequestData<'o> for Write {
     fn data<'a>(request: &'a Request<'o, Self>) -> &'a [u8] {
         request.body.1
@@ -110,8 +332,10 @@ impl<'o> ReplyAll<'o> for Write {
 }
 
 impl<'o> RequestHandle<'o> for Fsync {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.fh
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.fh)
     }
 }
 
@@ -126,16 +350,27 @@ impl<'o> RequestFlags<'o> for Fsync {
 impl<'o> ReplyOk<'o> for Fsync {}
 
 impl<'o> RequestHandle<'o> for Flush {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.fh
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.fh)
+    }
+}
+
+// Always present: closing any descriptor must release the POSIX locks of its owner
+impl<'o> RequestLockOwner<'o> for Flush {
+    fn lock_owner(request: &Request<'o, Self>) -> Option<u64> {
+        Some(request.body.lock_owner)
     }
 }
 
 impl<'o> ReplyOk<'o> for Flush {}
 
 impl<'o> RequestHandle<'o> for Fsyncdir {
-    fn handle(request: &Request<'o, Self>) -> u64 {
-        request.body.fsync_in.fh
+    type Handle = DirHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        DirHandle(request.body.fsync_in.fh)
     }
 }
 
@@ -149,6 +384,45 @@ impl<'o> RequestFlags<'o> for Fsyncdir {
 
 impl<'o> ReplyOk<'o> for Fsyncdir {}
 
+impl<'o> RequestHandle<'o> for Poll {
+    type Handle = FileHandle;
+
+    fn handle(request: &Request<'o, Self>) -> Self::Handle {
+        FileHandle(request.body.fh)
+    }
+}
+
+impl<'o> RequestPoll<'o> for Poll {
+    fn events(request: &Request<'o, Self>) -> PollFlags {
+        PollFlags::from_bits_truncate(request.body.events as _)
+    }
+
+    fn wakeup_handle(request: &Request<'o, Self>) -> Option<u64> {
+        let flags = proto::PollInFlags::from_bits_truncate(request.body.flags);
+
+        flags
+            .contains(proto::PollInFlags::SCHEDULE_NOTIFY)
+            .then_some(request.body.kh)
+    }
+}
+
+impl<'o> ReplyPoll<'o> for Poll {
+    fn ready(reply: Reply<'o, Self>, events: PollFlags) -> Done<'o> {
+        reply.single(&proto::PollOut {
+            revents: events.bits() as u16 as u32,
+            padding: Default::default(),
+        })
+    }
+}
+
+impl<'o> FromRequest<'o, Read> for ReadState {
+    fn from_request(request: &Request<'o, Read>) -> Self {
+        ReadState {
+            size: request.body.size,
+        }
+    }
+}
+
 impl<'o> FromRequest<'o, Write> for WriteState {
     fn from_request(request: &Request<'o, Write>) -> Self {
         let (body, data) = request.body;
@@ -161,6 +435,8 @@ impl<'o> FromRequest<'o, Write> for WriteState {
             );
         }
 
-        WriteState { size: body.size }
+        // Never acknowledge more than what was actually received
+        let size = body.size.min(data.len().try_into().unwrap_or(u32::MAX));
+        WriteState { size }
     }
 }
@@ -1,20 +1,35 @@
 use crate::{
-    io::{Entry, FsInfo, Ino, Interruptible, Known, Mode, Stat, Ttl},
+    io::{
+        Entry, FsInfo, Gid, Ino, Interruptible, Known, Mode, OpenFlags, PollFlags, RawAttrs,
+        SetTime, SetattrValid, Stat, Timestamp, Ttl, Uid, XattrNamespace,
+    },
     Done, Operation, Reply, Request,
 };
 
-use super::make_entry;
-use bytes::BufMut;
-use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+use super::{make_entry, xattr::XattrReadState};
+use bytes::{Buf, BufMut};
+use smallvec::SmallVec;
+use std::{
+    ffi::OsStr,
+    io::IoSlice,
+    ops::Range,
+    os::unix::{ffi::OsStrExt, io::RawFd},
+};
 
 pub use super::{
-    dir::{ReplyEntries, ReplyFound},
-    entry::{RequestDevice, RequestLink, RequestTarget},
-    global::ReplyFsInfo,
-    inode::{ReplyBlock, ReplyStat, RequestBlock, RequestForget},
-    open::{ReplyOpen, ReplyPermissionDenied},
-    rw::ReplyAll,
-    xattr::ReplyXattrRead,
+    dir::{ReplyEntries, ReplyFound, RequestPlus},
+    entry::{
+        ReplyNameTooLong, ReplyRemoved, RequestDevice, RequestLink, RequestRename, RequestTarget,
+    },
+    global::{ReplyFsInfo, ReplyInit},
+    inode::{ReplyBlock, ReplyStat, RequestBlock, RequestForget, RequestSetattr},
+    ioctl::{ReplyIoctl, RequestIoctl},
+    open::{ReplyOpen, ReplyPermissionDenied, RequestOpenMode},
+    rw::{
+        ReplyAll, ReplyEof, ReplyPoll, ReplySplice, RequestModified, RequestOpenFlags, RequestPoll,
+        RequestRange, RequestWriteback,
+    },
+    xattr::{ReplyXattrRead, RequestXattrNamespace},
 };
 
 pub trait RequestName<'o>: Operation<'o> {
@@ -30,7 +45,12 @@ pub trait RequestOffset<'o>: Operation<'o> {
 }
 
 pub trait RequestHandle<'o>: Operation<'o> {
-    fn handle(request: &Request<'o, Self>) -> u64;
+    type Handle: Copy;
+    fn handle(request: &Request<'o, Self>) -> Self::Handle;
+}
+
+pub trait RequestLockOwner<'o>: Operation<'o> {
+    fn lock_owner(request: &Request<'o, Self>) -> Option<u64>;
 }
 
 pub trait RequestData<'o>: Operation<'o> {
@@ -44,6 +64,8 @@ pub trait RequestFlags<'o>: Operation<'o> {
 
 pub trait RequestMode<'o>: Operation<'o> {
     fn mode(request: &Request<'o, Self>) -> Mode;
+
+    // DONT_MASK is never negotiated, so the kernel has already applied this to mode()
     fn umask(request: &Request<'o, Self>) -> Mode;
 }
 
@@ -56,9 +78,11 @@ pub trait ReplyOk<'o>: Operation<'o> {
 pub trait ReplyKnown<'o>: Operation<'o> {
     fn known(reply: Reply<'o, Self>, entry: impl Known, ttl: Ttl) -> Done<'o> {
         let (attrs, attrs_ttl) = entry.inode().attrs();
-        let attrs = attrs.finish(entry.inode());
+        let root = reply.session.root();
+        let attrs = attrs.finish(entry.inode(), root);
 
-        let done = reply.single(&make_entry((entry.inode().ino(), ttl), (attrs, attrs_ttl)));
+        let ino = entry.inode().ino().swap_root(root);
+        let done = reply.single(&make_entry((ino, ttl), (attrs, attrs_ttl)));
         entry.unveil();
 
         done
@@ -89,6 +113,27 @@ pub trait ReplyGather<'o>: Operation<'o> {
     fn gather(reply: Reply<'o, Self>, fragments: &[&[u8]]) -> Done<'o> {
         reply.chain(crate::util::OutputChain::tail(fragments))
     }
+
+    fn buf(reply: Reply<'o, Self>, mut buf: impl Buf) -> Done<'o> {
+        let mut chunks = vec![IoSlice::new(&[]); 8];
+
+        loop {
+            let count = buf.chunks_vectored(&mut chunks);
+            let vectored: usize = chunks[..count].iter().map(|chunk| chunk.len()).sum();
+
+            if vectored == buf.remaining() {
+                let fragments: SmallVec<[&[u8]; 8]> =
+                    chunks[..count].iter().map(|chunk| &**chunk).collect();
+
+                break Self::gather(reply, &fragments);
+            } else if count < chunks.len() {
+                // Buf implementations without chunks_vectored() only expose one chunk at a time
+                break Self::slice(reply, buf.copy_to_bytes(buf.remaining()));
+            }
+
+            chunks.resize(2 * chunks.len(), IoSlice::new(&[]));
+        }
+    }
 }
 
 impl<'o, O: Operation<'o>> Request<'o, O> {
@@ -113,13 +158,69 @@ impl<'o, O: Operation<'o>> Request<'o, O> {
         O::offset(self)
     }
 
-    pub fn handle(&self) -> u64
+    pub fn range(&self) -> Option<Range<u64>>
+    where
+        O: RequestRange<'o>,
+    {
+        O::range(self)
+    }
+
+    pub fn handle(&self) -> O::Handle
     where
         O: RequestHandle<'o>,
     {
         O::handle(self)
     }
 
+    pub fn lock_owner(&self) -> Option<u64>
+    where
+        O: RequestLockOwner<'o>,
+    {
+        O::lock_owner(self)
+    }
+
+    pub fn is_plus(&self) -> bool
+    where
+        O: RequestPlus<'o>,
+    {
+        O::is_plus(self)
+    }
+
+    pub fn is_writeback(&self) -> bool
+    where
+        O: RequestWriteback<'o>,
+    {
+        O::is_writeback(self)
+    }
+
+    pub fn namespace(&self) -> Option<XattrNamespace>
+    where
+        O: RequestXattrNamespace<'o>,
+    {
+        O::namespace(self)
+    }
+
+    pub fn open_flags(&self) -> OpenFlags
+    where
+        O: RequestOpenFlags<'o>,
+    {
+        O::open_flags(self)
+    }
+
+    pub fn is_direct(&self) -> bool
+    where
+        O: RequestOpenFlags<'o>,
+    {
+        O::is_direct(self)
+    }
+
+    pub fn modified(&self) -> Option<Timestamp>
+    where
+        O: RequestModified<'o>,
+    {
+        O::modified(self)
+    }
+
     pub fn data(&self) -> &[u8]
     where
         O: RequestData<'o>,
@@ -127,6 +228,15 @@ impl<'o, O: Operation<'o>> Request<'o, O> {
         O::data(self)
     }
 
+    // Boundaries of the buffers that back data(), for writev(2) and the like. Requests are
+    // currently received into a single contiguous buffer, thus there's always one chunk
+    pub fn data_chunks(&self) -> impl ExactSizeIterator<Item = &[u8]>
+    where
+        O: RequestData<'o>,
+    {
+        std::iter::once(O::data(self))
+    }
+
     pub fn flags(&self) -> O::Flags
     where
         O: RequestFlags<'o>,
@@ -134,6 +244,20 @@ impl<'o, O: Operation<'o>> Request<'o, O> {
         O::flags(self)
     }
 
+    pub fn truncate(&self) -> bool
+    where
+        O: RequestOpenMode<'o>,
+    {
+        O::truncate(self)
+    }
+
+    pub fn append(&self) -> bool
+    where
+        O: RequestOpenMode<'o>,
+    {
+        O::append(self)
+    }
+
     pub fn mode(&self) -> Mode
     where
         O: RequestMode<'o>,
@@ -176,6 +300,69 @@ impl<'o, O: Operation<'o>> Request<'o, O> {
         O::source_ino(self)
     }
 
+    pub fn valid(&self) -> SetattrValid
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::valid(self)
+    }
+
+    pub fn new_mode(&self) -> Option<Mode>
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::new_mode(self)
+    }
+
+    pub fn new_owner(&self) -> (Option<Uid>, Option<Gid>)
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::new_owner(self)
+    }
+
+    pub fn new_size(&self) -> Option<u64>
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::new_size(self)
+    }
+
+    pub fn new_atime(&self) -> Option<SetTime>
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::new_atime(self)
+    }
+
+    pub fn new_mtime(&self) -> Option<SetTime>
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::new_mtime(self)
+    }
+
+    pub fn new_ctime(&self) -> Option<Timestamp>
+    where
+        O: RequestSetattr<'o>,
+    {
+        O::new_ctime(self)
+    }
+
+    pub fn new_dir(&self) -> Ino
+    where
+        O: RequestRename<'o>,
+    {
+        O::new_dir(self)
+    }
+
+    pub fn new_name(&self) -> &OsStr
+    where
+        O: RequestRename<'o>,
+    {
+        O::new_name(self)
+    }
+
     pub fn block(&self) -> u64
     where
         O: RequestBlock<'o>,
@@ -189,6 +376,34 @@ impl<'o, O: Operation<'o>> Request<'o, O> {
     {
         O::block_size(self)
     }
+
+    pub fn command(&self) -> u32
+    where
+        O: RequestIoctl<'o>,
+    {
+        O::command(self)
+    }
+
+    pub fn argument(&self) -> u64
+    where
+        O: RequestIoctl<'o>,
+    {
+        O::argument(self)
+    }
+
+    pub fn events(&self) -> PollFlags
+    where
+        O: RequestPoll<'o>,
+    {
+        O::events(self)
+    }
+
+    pub fn wakeup_handle(&self) -> Option<u64>
+    where
+        O: RequestPoll<'o>,
+    {
+        O::wakeup_handle(self)
+    }
 }
 
 impl<'o, O: Operation<'o>> Reply<'o, O> {
@@ -199,6 +414,13 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::ok(self)
     }
 
+    pub fn ok_and_invalidate(self, parent: Ino, name: &OsStr) -> Done<'o>
+    where
+        O: ReplyRemoved<'o>,
+    {
+        O::ok_and_invalidate(self, parent, name)
+    }
+
     pub fn known(self, entry: impl Known, ttl: Ttl) -> Done<'o>
     where
         O: ReplyKnown<'o>,
@@ -220,6 +442,24 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::permission_denied(self)
     }
 
+    pub fn name_too_long(self) -> Done<'o>
+    where
+        O: ReplyNameTooLong<'o>,
+    {
+        O::name_too_long(self)
+    }
+
+    pub fn allow_if(self, allowed: bool) -> Done<'o>
+    where
+        O: ReplyOk<'o> + ReplyPermissionDenied<'o>,
+    {
+        if allowed {
+            self.ok()
+        } else {
+            self.permission_denied()
+        }
+    }
+
     pub fn stat(self, inode: &impl Stat) -> Done<'o>
     where
         O: ReplyStat<'o>,
@@ -227,20 +467,48 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::stat(self, inode)
     }
 
-    pub fn ok_with_handle(self, handle: u64) -> Done<'o>
+    pub fn stat_with_ttl(self, inode: &impl Stat, ttl: Ttl) -> Done<'o>
+    where
+        O: ReplyStat<'o>,
+    {
+        O::stat_with_ttl(self, inode, ttl)
+    }
+
+    pub fn stat_fresh(self, inode: &impl Stat) -> Done<'o>
+    where
+        O: ReplyStat<'o>,
+    {
+        O::stat_fresh(self, inode)
+    }
+
+    pub fn raw_attrs(self, attrs: &RawAttrs, ttl: Ttl) -> Done<'o>
+    where
+        O: ReplyStat<'o>,
+    {
+        O::raw_attrs(self, attrs, ttl)
+    }
+
+    pub fn ok_with_handle(self, handle: O::Handle) -> Done<'o>
     where
         O: ReplyOpen<'o> + ReplyOk<'o>,
     {
         O::ok_with_handle(self, handle)
     }
 
-    pub fn known_with_handle(self, known: impl Known, ttl: Ttl, handle: u64) -> Done<'o>
+    pub fn known_with_handle(self, known: impl Known, ttl: Ttl, handle: O::Handle) -> Done<'o>
     where
         O: ReplyOpen<'o> + ReplyKnown<'o>,
     {
         O::known_with_handle(self, known, ttl, handle)
     }
 
+    pub fn ok_for(self, inode: &impl Stat, handle: O::Handle) -> Done<'o>
+    where
+        O: ReplyOpen<'o> + ReplyOk<'o>,
+    {
+        O::ok_for(self, inode, handle)
+    }
+
     pub fn force_direct_io(&mut self)
     where
         O: ReplyOpen<'o>,
@@ -248,6 +516,55 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::force_direct_io(self)
     }
 
+    pub fn no_open_support(&mut self) -> bool
+    where
+        O: ReplyInit<'o>,
+    {
+        O::no_open_support(self)
+    }
+
+    pub fn no_opendir_support(&mut self) -> bool
+    where
+        O: ReplyInit<'o>,
+    {
+        O::no_opendir_support(self)
+    }
+
+    pub fn root(self, root: &impl Stat) -> Done<'o>
+    where
+        O: ReplyInit<'o>,
+    {
+        O::root(self, root)
+    }
+
+    pub fn max_write(&mut self, bytes: u32)
+    where
+        O: ReplyInit<'o>,
+    {
+        O::max_write(self, bytes)
+    }
+
+    pub fn writeback_cache(&mut self) -> bool
+    where
+        O: ReplyInit<'o>,
+    {
+        O::writeback_cache(self)
+    }
+
+    pub fn keep_cache(&mut self)
+    where
+        O: ReplyOpen<'o>,
+    {
+        O::keep_cache(self)
+    }
+
+    pub fn cache_dir(&mut self)
+    where
+        O: ReplyOpen<'o>,
+    {
+        O::cache_dir(self)
+    }
+
     pub fn non_seekable(&mut self)
     where
         O: ReplyOpen<'o>,
@@ -262,6 +579,13 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::is_stream(self)
     }
 
+    pub fn found(self, entry: impl Known, entry_ttl: Ttl, attr_ttl: Ttl) -> Done<'o>
+    where
+        O: ReplyFound<'o>,
+    {
+        O::found(self, entry, entry_ttl, attr_ttl)
+    }
+
     pub fn not_found_for(self, ttl: Ttl) -> Done<'o>
     where
         O: ReplyFound<'o>,
@@ -283,6 +607,20 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::end(self)
     }
 
+    pub fn remaining(&self) -> usize
+    where
+        O: ReplyEntries<'o>,
+    {
+        O::remaining(self)
+    }
+
+    pub fn would_fit(&self, name_len: usize) -> bool
+    where
+        O: ReplyEntries<'o>,
+    {
+        O::would_fit(self, name_len)
+    }
+
     pub fn all(self) -> Done<'o>
     where
         O: ReplyAll<'o>,
@@ -290,6 +628,29 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::all(self)
     }
 
+    pub fn eof(self) -> Done<'o>
+    where
+        O: ReplyEof<'o>,
+    {
+        O::eof(self)
+    }
+
+    pub fn splice_from(self, fd: RawFd, offset: u64, len: usize) -> Done<'o>
+    where
+        O: ReplySplice<'o>,
+    {
+        O::splice_from(self, fd, offset, len)
+    }
+
+    pub fn stream<I>(self, fragments: I) -> Done<'o>
+    where
+        O: ReplyXattrRead<'o> + Operation<'o, ReplyState = XattrReadState>,
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        O::stream(self, fragments)
+    }
+
     pub fn buffered<B>(self, buffer: B) -> Reply<'o, O::Buffered>
     where
         O: ReplyBuffered<'o, B>,
@@ -312,6 +673,13 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::slice(self, slice)
     }
 
+    pub fn buf(self, buf: impl Buf) -> Done<'o>
+    where
+        O: ReplyGather<'o>,
+    {
+        O::buf(self, buf)
+    }
+
     pub fn gather(self, fragments: &[&[u8]]) -> Done<'o>
     where
         O: ReplyGather<'o>,
@@ -326,6 +694,13 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::info(self, info)
     }
 
+    pub fn unknown(self) -> Done<'o>
+    where
+        O: ReplyFsInfo<'o>,
+    {
+        O::unknown(self)
+    }
+
     pub fn requires_size(self, size: u32) -> Done<'o>
     where
         O: ReplyXattrRead<'o>,
@@ -340,10 +715,38 @@ impl<'o, O: Operation<'o>> Reply<'o, O> {
         O::buffer_too_small(self)
     }
 
+    pub fn empty_value(self) -> Done<'o>
+    where
+        O: ReplyXattrRead<'o> + Operation<'o, ReplyState = XattrReadState>,
+    {
+        O::empty_value(self)
+    }
+
     pub fn block(self, block: u64) -> Done<'o>
     where
         O: ReplyBlock<'o>,
     {
         O::block(self, block)
     }
+
+    pub fn ioctl(self, result: i32, output: &[u8]) -> Done<'o>
+    where
+        O: ReplyIoctl<'o>,
+    {
+        O::ioctl(self, result, output)
+    }
+
+    pub fn retry_ioctl(self, input: &[(u64, u64)], output: &[(u64, u64)]) -> Done<'o>
+    where
+        O: ReplyIoctl<'o>,
+    {
+        O::retry_ioctl(self, input, output)
+    }
+
+    pub fn ready(self, events: PollFlags) -> Done<'o>
+    where
+        O: ReplyPoll<'o>,
+    {
+        O::ready(self, events)
+    }
 }
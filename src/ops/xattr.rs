@@ -1,9 +1,13 @@
 use super::{
     c_to_os,
     traits::{ReplyGather, ReplyNotFound, ReplyOk, RequestData, RequestName, RequestSize},
+    FromRequest,
 };
 
-use crate::{proto, sealed::Sealed, util::OutputChain, Done, Errno, Operation, Reply, Request};
+use crate::{
+    io::XattrNamespace, proto, sealed::Sealed, util::OutputChain, Done, Errno, Operation, Reply,
+    Request,
+};
 use std::ffi::{CStr, OsStr};
 
 pub enum Setxattr {}
@@ -15,12 +19,71 @@ pub struct XattrReadState {
     size: u32,
 }
 
+// A zero size asks for the size that a value would need, an empty value is a different thing
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum XattrFit {
+    Probe(u32),
+    TooSmall,
+    Fits,
+}
+
 pub trait ReplyXattrRead<'o>: Operation<'o> {
     fn requires_size(reply: Reply<'o, Self>, size: u32) -> Done<'o>;
 
     fn buffer_too_small(reply: Reply<'o, Self>) -> Done<'o> {
         reply.fail(Errno::ERANGE)
     }
+
+    // The value (or list) exists but is zero-length, unlike not_found(). This is right both for
+    // size probes and actual reads
+    fn empty_value(reply: Reply<'o, Self>) -> Done<'o>
+    where
+        Self: Operation<'o, ReplyState = XattrReadState>,
+    {
+        match reply.state.fit(0) {
+            XattrFit::Probe(size) => Self::requires_size(reply, size),
+            XattrFit::TooSmall | XattrFit::Fits => reply.empty(),
+        }
+    }
+
+    // Fragments are produced one at a time and only buffered up to the caller's size, bailing
+    // out with ERANGE as soon as they overflow it
+    fn stream<I>(reply: Reply<'o, Self>, fragments: I) -> Done<'o>
+    where
+        Self: Operation<'o, ReplyState = XattrReadState>,
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let capacity = reply.state.size as usize;
+        if capacity == 0 {
+            let size = fragments
+                .into_iter()
+                .map(|fragment| fragment.as_ref().len())
+                .sum::<usize>()
+                .try_into()
+                .expect("Extremely large xattr");
+
+            return Self::requires_size(reply, size);
+        }
+
+        let mut buffer = Vec::new();
+        for fragment in fragments {
+            let fragment = fragment.as_ref();
+            if buffer.len() + fragment.len() > capacity {
+                return Self::buffer_too_small(reply);
+            }
+
+            buffer.extend_from_slice(fragment);
+        }
+
+        reply.chain(OutputChain::tail(&[&buffer]))
+    }
+}
+
+pub trait RequestXattrNamespace<'o>: RequestName<'o> {
+    fn namespace(request: &Request<'o, Self>) -> Option<XattrNamespace> {
+        XattrNamespace::of(Self::name(request))
+    }
 }
 
 impl Sealed for Setxattr {}
@@ -49,6 +112,8 @@ impl<'o> Operation<'o> for Removexattr {
     type ReplyState = ();
 }
 
+impl<'o> RequestXattrNamespace<'o> for Setxattr {}
+
 impl<'o> RequestName<'o> for Setxattr {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
         let (_header, name, _value) = request.body;
@@ -78,6 +143,8 @@ impl<'o> RequestSize<'o> for Getxattr {
     }
 }
 
+impl<'o> RequestXattrNamespace<'o> for Getxattr {}
+
 impl<'o> RequestName<'o> for Getxattr {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
         c_to_os(request.body.1)
@@ -99,13 +166,11 @@ impl<'o> ReplyGather<'o> for Getxattr {
             .try_into()
             .expect("Extremely large xattr");
 
-        if reply.state.size == 0 {
-            return reply.requires_size(size);
-        } else if reply.state.size < size {
-            return reply.buffer_too_small();
+        match reply.state.fit(size) {
+            XattrFit::Probe(size) => reply.requires_size(size),
+            XattrFit::TooSmall => reply.buffer_too_small(),
+            XattrFit::Fits => reply.chain(OutputChain::tail(fragments)),
         }
-
-        reply.chain(OutputChain::tail(fragments))
     }
 }
 
@@ -141,6 +206,8 @@ impl<'o> ReplyXattrRead<'o> for Listxattr {
     }
 }
 
+impl<'o> RequestXattrNamespace<'o> for Removexattr {}
+
 impl<'o> RequestName<'o> for Removexattr {
     fn name<'a>(request: &'a Request<'o, Self>) -> &'a OsStr {
         c_to_os(request.body)
@@ -154,3 +221,63 @@ impl<'o> ReplyNotFound<'o> for Removexattr {
         reply.fail(Errno::ENODATA)
     }
 }
+
+impl XattrReadState {
+    fn fit(&self, size: u32) -> XattrFit {
+        if self.size == 0 {
+            XattrFit::Probe(size)
+        } else if self.size < size {
+            XattrFit::TooSmall
+        } else {
+            XattrFit::Fits
+        }
+    }
+}
+
+impl<'o> FromRequest<'o, Getxattr> for XattrReadState {
+    fn from_request(request: &Request<'o, Getxattr>) -> Self {
+        XattrReadState {
+            size: request.size(),
+        }
+    }
+}
+
+impl<'o> FromRequest<'o, Listxattr> for XattrReadState {
+    fn from_request(request: &Request<'o, Listxattr>) -> Self {
+        XattrReadState {
+            size: request.size(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespace_is_the_first_component() {
+        let of = |name: &str| XattrNamespace::of(OsStr::new(name));
+
+        assert_eq!(of("security.selinux"), Some(XattrNamespace::Security));
+        assert_eq!(of("system.posix_acl_access"), Some(XattrNamespace::System));
+        assert_eq!(of("trusted.overlay.opaque"), Some(XattrNamespace::Trusted));
+        assert_eq!(of("user.mime_type"), Some(XattrNamespace::User));
+        assert_eq!(of("user"), None);
+        assert_eq!(of("securityx.foo"), None);
+    }
+
+    #[test]
+    fn zero_size_is_a_probe() {
+        let probe = XattrReadState { size: 0 };
+        assert_eq!(probe.fit(0), XattrFit::Probe(0));
+        assert_eq!(probe.fit(12), XattrFit::Probe(12));
+    }
+
+    #[test]
+    fn empty_value_fits_any_read() {
+        let read = XattrReadState { size: 1 };
+        assert_eq!(read.fit(0), XattrFit::Fits);
+        assert_eq!(read.fit(1), XattrFit::Fits);
+        assert_eq!(read.fit(2), XattrFit::TooSmall);
+    }
+}
@@ -10,7 +10,14 @@ use std::{convert::TryFrom, ffi::CStr, fmt};
 pub const ROOT_ID: u64 = 1;
 pub const MAJOR_VERSION: u32 = 7;
 pub const TARGET_MINOR_VERSION: u32 = 32;
-pub const REQUIRED_MINOR_VERSION: u32 = 31;
+pub const REQUIRED_MINOR_VERSION: u32 = 12;
+
+// InitOut was truncated after max_write before 7.23
+pub const COMPAT_22_MINOR_VERSION: u32 = 22;
+pub const COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
+// Kernel's FUSE_MAX_MAX_PAGES, larger max_pages values are clamped to this
+pub const MAX_MAX_PAGES: usize = 256;
 
 pub const MIN_READ_SIZE: usize = 8192;
 pub const DIRENT_ALIGNMENT_BITS: usize = 3;
@@ -20,7 +27,7 @@ pub trait Structured<'o>: Sized {
 
     fn toplevel_from(bytes: &'o [u8], header: &InHeader) -> FuseResult<Self> {
         match Self::split_from(bytes, header, true)? {
-            (ok, end) if end.is_empty() => Ok(ok),
+            (ok, []) => Ok(ok),
             _ => Err(FuseError::BadLength),
         }
     }
@@ -52,8 +59,9 @@ pub struct OutHeader {
     pub unique: u64,
 }
 
-#[derive(TryFromPrimitive, Copy, Clone, Debug)]
+#[derive(TryFromPrimitive, Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u32)]
+#[non_exhaustive]
 pub enum Opcode {
     Lookup = 1,
     Forget = 2,
@@ -100,8 +108,10 @@ pub enum Opcode {
     Rename2 = 45,
     Lseek = 46,
     CopyFileRange = 47,
+    CuseInit = 4096,
 }
 
+#[allow(dead_code)]
 #[derive(TryFromPrimitive, Copy, Clone)]
 #[repr(i32)]
 pub enum NotifyCode {
@@ -185,6 +195,12 @@ pub struct GetattrIn {
     pub fh: u64,
 }
 
+bitflags! {
+    pub struct GetattrFlags: u32 {
+        const FH = 1 << 0;
+    }
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct AttrOut {
@@ -215,6 +231,23 @@ pub struct SetattrIn {
     pub unused2: u32,
 }
 
+bitflags! {
+    pub struct SetattrValid: u32 {
+        const MODE         = 1 << 0;
+        const UID          = 1 << 1;
+        const GID          = 1 << 2;
+        const SIZE         = 1 << 3;
+        const ATIME        = 1 << 4;
+        const MTIME        = 1 << 5;
+        const FH           = 1 << 6;
+        const ATIME_NOW    = 1 << 7;
+        const MTIME_NOW    = 1 << 8;
+        const LOCKOWNER    = 1 << 9;
+        const CTIME        = 1 << 10;
+        const KILL_SUIDGID = 1 << 11;
+    }
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct MknodIn {
@@ -280,6 +313,12 @@ pub struct ReadIn {
     pub padding: u32,
 }
 
+bitflags! {
+    pub struct ReadFlags: u32 {
+        const LOCKOWNER = 1 << 1;
+    }
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct WriteIn {
@@ -292,6 +331,14 @@ pub struct WriteIn {
     pub padding: u32,
 }
 
+bitflags! {
+    pub struct WriteFlags: u32 {
+        const CACHE     = 1 << 0;
+        const LOCKOWNER = 1 << 1;
+        const KILL_PRIV = 1 << 2;
+    }
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct WriteOut {
@@ -323,6 +370,13 @@ pub struct ReleaseIn {
     pub lock_owner: u64,
 }
 
+bitflags! {
+    pub struct ReleaseFlags: u32 {
+        const FLUSH        = 1 << 0;
+        const FLOCK_UNLOCK = 1 << 1;
+    }
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct FsyncIn {
@@ -435,6 +489,79 @@ bitflags! {
     }
 }
 
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct CuseInitIn {
+    pub major: u32,
+    pub minor: u32,
+    pub unused: u32,
+    pub flags: u32,
+}
+
+bitflags! {
+    pub struct CuseInitFlags: u32 {
+        const UNRESTRICTED_IOCTL = 1 << 0;
+    }
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct CuseInitOut {
+    pub major: u32,
+    pub minor: u32,
+    pub unused: u32,
+    pub flags: u32,
+    pub max_read: u32,
+    pub max_write: u32,
+    pub dev_major: u32,
+    pub dev_minor: u32,
+    pub spare: [u32; 10],
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct NotifyPollWakeupOut {
+    pub kh: u64,
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct NotifyInvalEntryOut {
+    pub parent: u64,
+    pub namelen: u32,
+    pub padding: u32,
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct NotifyStoreOut {
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct NotifyRetrieveOut {
+    pub notify_unique: u64,
+    pub nodeid: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub padding: u32,
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct NotifyRetrieveIn {
+    pub dummy1: u64,
+    pub offset: u64,
+    pub size: u32,
+    pub dummy2: u32,
+    pub dummy3: u64,
+    pub dummy4: u64,
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct OpendirIn {
@@ -534,6 +661,33 @@ pub struct IoctlIn {
     pub out_size: u32,
 }
 
+bitflags! {
+    pub struct IoctlFlags: u32 {
+        const COMPAT       = 1 << 0;
+        const UNRESTRICTED = 1 << 1;
+        const RETRY        = 1 << 2;
+        const BITS_32      = 1 << 3;
+        const DIR          = 1 << 4;
+        const COMPAT_X32   = 1 << 5;
+    }
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct IoctlOut {
+    pub result: i32,
+    pub flags: u32,
+    pub in_iovs: u32,
+    pub out_iovs: u32,
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct IoctlIovec {
+    pub base: u64,
+    pub len: u64,
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct PollIn {
@@ -543,6 +697,19 @@ pub struct PollIn {
     pub events: u32,
 }
 
+bitflags! {
+    pub struct PollInFlags: u32 {
+        const SCHEDULE_NOTIFY = 1 << 0;
+    }
+}
+
+#[derive(Pod, Zeroable, Copy, Clone)]
+#[repr(C)]
+pub struct PollOut {
+    pub revents: u32,
+    pub padding: u32,
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct ForgetOne {
@@ -581,6 +748,14 @@ pub struct Rename2In {
     pub padding: u32,
 }
 
+bitflags! {
+    pub struct RenameFlags: u32 {
+        const NOREPLACE = 1 << 0;
+        const EXCHANGE = 1 << 1;
+        const WHITEOUT = 1 << 2;
+    }
+}
+
 #[derive(Pod, Zeroable, Copy, Clone)]
 #[repr(C)]
 pub struct LseekIn {
@@ -721,3 +896,37 @@ impl fmt::Display for Opcode {
         write!(fmt, "{:?} ({})", self, *self as u32)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+
+    fn split_names(bytes: &[u8]) -> FuseResult<(&CStr, &CStr)> {
+        Structured::toplevel_from(bytes, &InHeader::zeroed())
+    }
+
+    #[test]
+    fn names_split_at_first_nul() {
+        let (name, target) = split_names(b"link\0../a/b/c\0").unwrap();
+        assert_eq!(name.to_bytes(), b"link");
+        assert_eq!(target.to_bytes(), b"../a/b/c");
+
+        let (name, target) = split_names(b"a\0\0").unwrap();
+        assert_eq!(name.to_bytes(), b"a");
+        assert_eq!(target.to_bytes(), b"");
+    }
+
+    #[test]
+    fn malformed_names_are_rejected() {
+        assert!(matches!(split_names(b"link"), Err(FuseError::Truncated)));
+        assert!(matches!(
+            split_names(b"link\0a\0b\0"),
+            Err(FuseError::BadLength)
+        ));
+        assert!(matches!(
+            split_names(b"link\0target"),
+            Err(FuseError::BadLength)
+        ));
+    }
+}
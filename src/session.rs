@@ -1,27 +1,41 @@
 use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{OsStr, OsString},
     future::Future,
     io,
     marker::PhantomData,
     ops::ControlFlow,
-    os::unix::io::{IntoRawFd, RawFd},
+    os::unix::{
+        ffi::OsStrExt,
+        io::{IntoRawFd, RawFd},
+        net::UnixStream,
+    },
+    panic::{self, AssertUnwindSafe},
     path::PathBuf,
-    sync::{Arc, Mutex},
+    pin::Pin,
+    sync::{
+        atomic::{self, AtomicU32, AtomicU64, AtomicUsize},
+        Arc, Mutex,
+    },
+    task::Poll,
 };
 
 use nix::{
-    fcntl::{fcntl, FcntlArg, OFlag},
-    sys::uio::{writev, IoVec},
-    unistd::read,
+    fcntl::{fcntl, splice, FcntlArg, OFlag, SpliceFFlags},
+    sys::uio::{pread, writev, IoVec},
+    unistd::{pipe2, read, write},
 };
 
 use tokio::{
     io::unix::AsyncFd,
-    sync::{broadcast, OwnedSemaphorePermit, Semaphore},
+    sync::{broadcast, oneshot, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
 };
 
 use crate::{
     error::MountError,
-    mount::unmount_sync,
+    io::{InitFlags, Ino},
+    mount::{abort_sync, unmount_sync},
     ops::{self, FromRequest},
     proto::{self, InHeader, Structured},
     util::{page_size, DumbFd, OutputChain},
@@ -34,15 +48,56 @@ use smallvec::SmallVec;
 pub struct Start {
     session_fd: DumbFd,
     mountpoint: PathBuf,
+    fusermount_cmd: OsString,
+    unmount_on_drop: bool,
+    fusermount_comm: Option<UnixStream>,
+}
+
+#[derive(Default)]
+pub struct StartOptions {
+    max_concurrent_data_ops: Option<usize>,
+    buffer_pages: Option<usize>,
+    preserve_root_ino: bool,
+}
+
+pub struct CuseDevice<'a> {
+    pub name: &'a str,
+    pub major: u32,
+    pub minor: u32,
+    // Allows ioctls with arbitrary layouts through Reply<Ioctl>::retry_ioctl()
+    pub unrestricted_ioctl: bool,
 }
 
 pub struct Session {
     session_fd: AsyncFd<RawFd>,
     interrupt_tx: broadcast::Sender<u64>,
+    recent_interrupts: Mutex<VecDeque<u64>>,
     buffers: Mutex<Vec<Buffer>>,
     buffer_semaphore: Arc<Semaphore>,
+    buffer_waiters: AtomicUsize,
+    buffer_exhaustions: AtomicU64,
     buffer_pages: usize,
+    data_semaphore: Option<Arc<Semaphore>>,
     mountpoint: Mutex<Option<PathBuf>>,
+    fusermount_cmd: OsString,
+    unmount_on_drop: bool,
+    _fusermount_comm: Option<UnixStream>,
+    splice_write: bool,
+    init_flags: AtomicU32,
+    preserve_root_ino: bool,
+    root: AtomicU64,
+    next_notify_unique: AtomicU64,
+    retrievals: Mutex<HashMap<u64, oneshot::Sender<Vec<u8>>>>,
+}
+
+// Buffers are taken by Incoming::owned() until the Owned is dropped. Frequent exhaustions
+// mean that requests wait for each other, see Session::buffer_stats()
+#[derive(Copy, Clone, Debug)]
+pub struct BufferStats {
+    pub total: usize,
+    pub available: usize,
+    pub waiters: usize,
+    pub exhaustions: u64,
 }
 
 pub struct Endpoint<'a> {
@@ -54,6 +109,7 @@ pub enum Dispatch<'o> {
     Lookup(Incoming<'o, ops::Lookup>),
     Forget(Incoming<'o, ops::Forget>),
     Getattr(Incoming<'o, ops::Getattr>),
+    Setattr(Incoming<'o, ops::Setattr>),
     Readlink(Incoming<'o, ops::Readlink>),
     Symlink(Incoming<'o, ops::Symlink>),
     Mknod(Incoming<'o, ops::Mknod>),
@@ -61,6 +117,7 @@ pub enum Dispatch<'o> {
     Unlink(Incoming<'o, ops::Unlink>),
     Rmdir(Incoming<'o, ops::Rmdir>),
     Link(Incoming<'o, ops::Link>),
+    Rename(Incoming<'o, ops::Rename>),
     Open(Incoming<'o, ops::Open>),
     Read(Incoming<'o, ops::Read>),
     Write(Incoming<'o, ops::Write>),
@@ -79,6 +136,42 @@ pub enum Dispatch<'o> {
     Access(Incoming<'o, ops::Access>),
     Create(Incoming<'o, ops::Create>),
     Bmap(Incoming<'o, ops::Bmap>),
+    Destroy(Incoming<'o, ops::Destroy>),
+    Ioctl(Incoming<'o, ops::Ioctl>),
+    Poll(Incoming<'o, ops::Poll>),
+}
+
+// Expands to a match over a Dispatch, replying ENOSYS to operations that aren't listed (except
+// for Statfs, which falls back to Reply::unknown()):
+//
+//     dispatch!(dispatch, fs, {
+//         Lookup => lookup.await,
+//         Getattr => getattr,
+//     })
+#[macro_export]
+macro_rules! dispatch {
+    ($dispatch:expr, $fs:expr, { $($op:ident => $method:ident $(.$await:tt)?),* $(,)? }) => {
+        match $dispatch {
+            $(
+                $crate::session::Dispatch::$op(incoming) => match incoming.op() {
+                    Ok(op) => $fs.$method(op)$(.$await)?,
+                    Err(done) => done,
+                },
+            )*
+
+            #[allow(unreachable_patterns)]
+            $crate::session::Dispatch::Statfs(incoming) => match incoming.op() {
+                Ok((_, reply)) => reply.unknown(),
+                Err(done) => done,
+            },
+
+            #[allow(unreachable_patterns)]
+            dispatch => {
+                let (_, reply) = dispatch.op();
+                reply.not_implemented()
+            }
+        }
+    };
 }
 
 pub struct Incoming<'o, O: Operation<'o>> {
@@ -107,22 +200,150 @@ impl Session {
     pub fn unmount_sync(&self) -> Result<(), MountError> {
         let mountpoint = self.mountpoint.lock().unwrap().take();
         if let Some(mountpoint) = &mountpoint {
-            unmount_sync(mountpoint)?;
+            unmount_sync(&self.fusermount_cmd, mountpoint)?;
         }
 
         Ok(())
     }
 
+    // For testing how a filesystem copes with an aborted connection: the kernel fails pending
+    // and further requests (with ECONNABORTED if ops::Init negotiated ABORT_ERROR, ENOTCONN
+    // otherwise) and Endpoint::receive() stops. Needs write access to fusectl
+    pub fn abort(&self) -> FuseResult<()> {
+        let mountpoint = self.mountpoint.lock().unwrap().clone();
+        let mountpoint = mountpoint.ok_or(Errno::ENOTCONN)?;
+
+        abort_sync(&mountpoint)?;
+        Ok(())
+    }
+
+    // The /dev/fuse fd, for ioctls that aren't wrapped (such as FUSE_DEV_IOC_CLONE) or for
+    // polling it elsewhere (see Endpoint::try_receive()). It remains owned by the session and
+    // must not be closed, nor be read from or written to behind its back. It's non-blocking
+    pub fn raw_fd(&self) -> RawFd {
+        *self.session_fd.get_ref()
+    }
+
+    pub fn buffer_stats(&self) -> BufferStats {
+        BufferStats {
+            total: SHARED_BUFFERS,
+            available: self.buffer_semaphore.available_permits(),
+            waiters: self.buffer_waiters.load(atomic::Ordering::Relaxed),
+            exhaustions: self.buffer_exhaustions.load(atomic::Ordering::Relaxed),
+        }
+    }
+
+    // Inode number of the filesystem's root, which is swapped with Ino::ROOT in every request
+    // and reply. It's Ino::ROOT itself unless set during Init
+    pub fn root(&self) -> Ino {
+        Ino(self.root.load(atomic::Ordering::Relaxed))
+    }
+
+    // Features that were agreed on during Init, both supported by the kernel and enabled by
+    // Reply<Init> (including opt-ins). Empty before Init and for CUSE sessions
+    pub fn active_flags(&self) -> InitFlags {
+        InitFlags::from_bits_truncate(self.init_flags.load(atomic::Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_active_flags(&self, flags: InitFlags) {
+        self.init_flags
+            .store(flags.bits(), atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_root(&self, root: Ino) {
+        if self.preserve_root_ino {
+            log::warn!(
+                "Ignoring root {} due to StartOptions::preserve_root_ino()",
+                root
+            );
+            return;
+        }
+
+        self.root.store(root.as_raw(), atomic::Ordering::Relaxed);
+    }
+
+    // The kernel reports failures (ENOENT, ENOTDIR, EBUSY...) synchronously
+    pub fn invalidate_entry(&self, parent: Ino, name: &OsStr) -> Result<(), Errno> {
+        let name = name.as_bytes();
+        let inval_entry = proto::NotifyInvalEntryOut {
+            parent: parent.swap_root(self.root()).as_raw(),
+            namelen: name.len().try_into().map_err(|_| Errno::ENAMETOOLONG)?,
+            padding: Default::default(),
+        };
+
+        let tail = [bytes_of(&inval_entry), name, b"\0"];
+        self.notify(proto::NotifyCode::InvalEntry, OutputChain::tail(&tail))
+    }
+
+    // Wakes up pollers of a handle, see Request<Poll>::wakeup_handle()
+    pub fn poll_wakeup(&self, wakeup_handle: u64) -> Result<(), Errno> {
+        let wakeup = proto::NotifyPollWakeupOut { kh: wakeup_handle };
+        self.notify(
+            proto::NotifyCode::Poll,
+            OutputChain::tail(&[bytes_of(&wakeup)]),
+        )
+    }
+
+    // Fills the kernel's page cache for an inode, as if the data had been read. Large stores are
+    // split into notifications of at most max_write bytes each, which are not atomic as a whole
+    pub fn store(&self, ino: Ino, offset: u64, data: &[u8]) -> Result<(), Errno> {
+        let max_write = ops::buffer_max_write(self.buffer_pages) as usize;
+        let nodeid = ino.swap_root(self.root()).as_raw();
+        for (offset, chunk) in store_chunks(offset, data, max_write)? {
+            let store = proto::NotifyStoreOut {
+                nodeid,
+                offset,
+                size: chunk.len().try_into().unwrap(),
+                padding: Default::default(),
+            };
+
+            let tail = [bytes_of(&store), chunk];
+            self.notify(proto::NotifyCode::Store, OutputChain::tail(&tail))?;
+        }
+
+        Ok(())
+    }
+
+    // Fetches data cached by the kernel. The answer arrives through Endpoint::receive(), so
+    // requests must keep being received elsewhere while this is awaited
+    pub async fn retrieve(&self, ino: Ino, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let notify_unique = self
+            .next_notify_unique
+            .fetch_add(1, atomic::Ordering::Relaxed);
+
+        let (tx, rx) = oneshot::channel();
+        self.retrievals.lock().unwrap().insert(notify_unique, tx);
+
+        let retrieve = proto::NotifyRetrieveOut {
+            notify_unique,
+            nodeid: ino.swap_root(self.root()).as_raw(),
+            offset,
+            size,
+            padding: Default::default(),
+        };
+
+        let tail = [bytes_of(&retrieve)];
+        if let Err(errno) = self.notify(proto::NotifyCode::Retrieve, OutputChain::tail(&tail)) {
+            self.retrievals.lock().unwrap().remove(&notify_unique);
+            return Err(errno);
+        }
+
+        // The sender is dropped if the session goes away before the kernel answers
+        rx.await.map_err(|_| Errno::ENOTCONN)
+    }
+
     pub(crate) fn ok(&self, unique: u64, output: OutputChain<'_>) -> FuseResult<()> {
         self.send(unique, 0, output)
     }
 
     pub(crate) fn fail(&self, unique: u64, mut errno: i32) -> FuseResult<()> {
-        if errno <= 0 {
+        // fs/fuse/dev.c rejects anything from ERESTARTSYS (512) onwards, leaving the request hanging
+        if !(1..=MAX_ERRNO).contains(&errno) {
             log::warn!(
-                "Attempted to fail req#{} with errno {} <= 0, coercing to ENOMSG",
+                "Attempted to fail req#{} with errno {} not in 1..={}, coercing to ENOMSG",
                 unique,
-                errno
+                errno,
+                MAX_ERRNO
             );
 
             errno = Errno::ENOMSG as i32;
@@ -131,6 +352,228 @@ impl Session {
         self.send(unique, -errno, OutputChain::empty())
     }
 
+    // Replies with up to `len` bytes read from `fd` at `offset`, without copying through userspace
+    // if the kernel allows it. Errors from `fd` are replied with, never propagated
+    pub(crate) fn splice_ok(
+        &self,
+        unique: u64,
+        fd: RawFd,
+        offset: u64,
+        len: usize,
+    ) -> FuseResult<()> {
+        let header_size = std::mem::size_of::<proto::OutHeader>();
+
+        let copy = || match read_at(fd, offset, len) {
+            Ok(data) => self.ok(unique, OutputChain::tail(&[&data])),
+            Err(errno) => self.fail(unique, errno as i32),
+        };
+
+        // Kernels before 7.14 can't splice into the device, and pipes might not be able to
+        // grow (see pipe-max-size in proc(5)), in both cases data is copied instead. Pipes are
+        // made anew for every reply on purpose: two pipe2() and F_SETPIPE_SZ calls are cheap next
+        // to the read, and a pipe kept per endpoint could hold leftovers from a failed reply
+        let pipes = if self.splice_write {
+            splice_pipe(len).and_then(|data| Ok((data, splice_pipe(header_size + len)?)))
+        } else {
+            Err(Errno::ENOSYS)
+        };
+
+        let ((data_rx, data_tx), (out_rx, out_tx)) = match pipes {
+            Ok(pipes) => pipes,
+            Err(_) => return copy(),
+        };
+
+        // The header must precede the data in the pipe, but its length is only known after
+        // splicing from `fd` (which might hit EOF early), so data is first staged in another pipe.
+        // Nothing has reached the device up to this point, so failures (such as EINVAL or ESPIPE
+        // from fds that can't be spliced from) fall back to copying, which replies with any error
+        let stage = || -> Result<usize, Errno> {
+            let mut offset: i64 = offset.try_into().map_err(|_| Errno::EINVAL)?;
+            let mut data_len = 0;
+
+            while data_len < len {
+                let flags = SpliceFFlags::SPLICE_F_MOVE;
+                let spliced = splice(
+                    fd,
+                    Some(&mut offset),
+                    data_tx.0,
+                    None,
+                    len - data_len,
+                    flags,
+                )?;
+
+                if spliced == 0 {
+                    break;
+                }
+
+                data_len += spliced;
+            }
+
+            let length = header_size + data_len;
+            let header = proto::OutHeader {
+                len: length as _,
+                error: 0,
+                unique,
+            };
+
+            if write(out_tx.0, bytes_of(&header))? != header_size {
+                return Err(Errno::EIO);
+            }
+
+            let mut moved = 0;
+            while moved < data_len {
+                let flags = SpliceFFlags::SPLICE_F_MOVE;
+                moved += splice(data_rx.0, None, out_tx.0, None, data_len - moved, flags)?;
+            }
+
+            Ok(length)
+        };
+
+        let length = match stage() {
+            Ok(length) => length,
+            Err(_) => return copy(),
+        };
+
+        // The whole reply must reach the device in a single call
+        let session_fd = *self.session_fd.get_ref();
+        let flags = SpliceFFlags::SPLICE_F_MOVE;
+        let written =
+            splice(out_rx.0, None, session_fd, None, length, flags).map_err(io::Error::from)?;
+
+        if written == length {
+            Ok(())
+        } else {
+            Err(FuseError::ShortWrite)
+        }
+    }
+
+    async fn cuse_handshake(
+        &mut self,
+        buffer: &mut Buffer,
+        device: &CuseDevice<'_>,
+    ) -> FuseResult<()> {
+        self.session_fd.readable().await?.retain_ready();
+        let bytes = read(*self.session_fd.get_ref(), &mut buffer.0).map_err(io::Error::from)?;
+
+        let (header, opcode) = InHeader::from_bytes(&buffer.0[..bytes])?;
+        let body = match opcode {
+            proto::Opcode::CuseInit => {
+                <&proto::CuseInitIn>::toplevel_from(&buffer.0[HEADER_END..bytes], &header)?
+            }
+
+            _ => {
+                log::error!(
+                    "First message from kernel is not CuseInit, but {:?}",
+                    opcode
+                );
+                return Err(FuseError::ProtocolInit);
+            }
+        };
+
+        // Unlike Init, there's no major version renegotiation for CUSE
+        if body.major != proto::MAJOR_VERSION || body.minor < proto::REQUIRED_MINOR_VERSION {
+            log::error!("Unsupported CUSE protocol {}.{}", body.major, body.minor);
+
+            self.fail(header.unique, Errno::EPROTONOSUPPORT as i32)?;
+            return Err(FuseError::UnsupportedProtocol {
+                major: body.major,
+                minor: body.minor,
+            });
+        }
+
+        if device.name.is_empty() || device.name.contains('\0') {
+            log::error!("Invalid CUSE device name: {:?}", device.name);
+
+            self.fail(header.unique, Errno::EINVAL as i32)?;
+            return Err(Errno::EINVAL.into());
+        }
+
+        let mut flags = proto::CuseInitFlags::empty();
+        if device.unrestricted_ioctl {
+            let kernel_flags = proto::CuseInitFlags::from_bits_truncate(body.flags);
+            if kernel_flags.contains(proto::CuseInitFlags::UNRESTRICTED_IOCTL) {
+                flags |= proto::CuseInitFlags::UNRESTRICTED_IOCTL;
+            } else {
+                log::warn!("Kernel does not support unrestricted CUSE ioctls");
+            }
+        }
+
+        let buffer_size = page_size() * self.buffer_pages;
+        let init_out = proto::CuseInitOut {
+            major: proto::MAJOR_VERSION,
+            minor: proto::TARGET_MINOR_VERSION,
+            unused: 0,
+            flags: flags.bits(),
+            max_read: buffer_size.try_into().unwrap(),
+            max_write: ops::buffer_max_write(self.buffer_pages),
+            dev_major: device.major,
+            dev_minor: device.minor,
+            spare: Default::default(),
+        };
+
+        let dev_info = format!("DEVNAME={}\0", device.name);
+        let tail = [bytes_of(&init_out), dev_info.as_bytes()];
+        self.ok(header.unique, OutputChain::tail(&tail))
+    }
+
+    fn notify(&self, code: proto::NotifyCode, output: OutputChain<'_>) -> Result<(), Errno> {
+        match self.send(0, code as i32, output) {
+            Ok(()) => Ok(()),
+            Err(FuseError::Io(error)) => {
+                Err(error.raw_os_error().map_or(Errno::EIO, Errno::from_i32))
+            }
+            Err(_) => Err(Errno::EIO),
+        }
+    }
+
+    // Notify replies get no reply of their own
+    fn retrieved(&self, header: &InHeader, body: &[u8]) {
+        let (retrieve_in, data) =
+            match <(&proto::NotifyRetrieveIn, &[u8])>::toplevel_from(body, header) {
+                Ok(parsed) => parsed,
+                Err(error) => {
+                    log::error!("Parsing notify reply {}: {:?}", header, error);
+                    return;
+                }
+            };
+
+        match self.retrievals.lock().unwrap().remove(&header.unique) {
+            Some(tx) => {
+                let size = (retrieve_in.size as usize).min(data.len());
+                let _ = tx.send(data[..size].to_vec());
+            }
+
+            None => log::warn!("Unexpected notify reply: {}", header),
+        }
+    }
+
+    // A caller wants a pending request to be interrupted, see Reply::interruptible(). This gets
+    // no reply of its own, either the target request completes or it's failed with EINTR
+    fn interrupt(&self, header: &InHeader, body: &[u8]) {
+        match <&proto::InterruptIn>::toplevel_from(body, header) {
+            // Nobody might be listening, which means that no request is interruptible right now
+            Ok(interrupt) => {
+                let mut recent = self.recent_interrupts.lock().unwrap();
+                if recent.len() == INTERRUPT_BROADCAST_CAPACITY {
+                    recent.pop_front();
+                }
+
+                recent.push_back(interrupt.unique);
+                drop(recent);
+
+                drop(self.interrupt_tx.send(interrupt.unique));
+            }
+
+            Err(error) => log::error!("Parsing interrupt {}: {:?}", header, error),
+        }
+    }
+
+    // Only the last INTERRUPT_BROADCAST_CAPACITY interrupts are remembered, see
+    // Reply::check_interrupt()
+    pub(crate) fn is_interrupted(&self, unique: u64) -> bool {
+        self.recent_interrupts.lock().unwrap().contains(&unique)
+    }
+
     pub(crate) fn interrupt_rx(&self) -> broadcast::Receiver<u64> {
         self.interrupt_tx.subscribe()
     }
@@ -166,7 +609,7 @@ impl Session {
             }
         };
 
-        //TODO: fake some decency by supporting a few older minor versions
+        // Request layouts haven't changed in incompatible ways since 7.12, see Init for the reply
         if !supported {
             log::error!(
                 "Unsupported protocol {}.{}; this build requires \
@@ -180,23 +623,40 @@ impl Session {
             );
 
             self.fail(header.unique, Errno::EPROTONOSUPPORT as i32)?;
-            return Err(FuseError::ProtocolInit);
+            return Err(FuseError::UnsupportedProtocol {
+                major: body.major,
+                minor: body.minor,
+            });
         }
 
-        let request = Request { header, body };
+        let kernel_flags = proto::InitFlags::from_bits_truncate(body.flags);
+        self.splice_write = kernel_flags.contains(proto::InitFlags::SPLICE_WRITE);
+
+        let request = Request {
+            header,
+            body,
+            root: Ino::ROOT,
+        };
+
         let reply = Reply {
             session: self,
             unique: header.unique,
             state: ops::InitState {
-                kernel_flags: proto::InitFlags::from_bits_truncate(body.flags),
+                kernel_flags,
+                opt_in_flags: proto::InitFlags::empty(),
+                kernel_minor: body.minor,
                 buffer_pages: self.buffer_pages,
+                max_write: None,
             },
+            guard: ReplyGuard::new(self, &header),
         };
 
         init((request, reply)).consume();
         Ok(Handshake::Done)
     }
 
+    // The device takes exactly one message per write(), failing with EINVAL if the header's length
+    // doesn't match, thus replies and notifications can't be batched into a single writev()
     fn send(&self, unique: u64, error: i32, output: OutputChain<'_>) -> FuseResult<()> {
         let after_header: usize = output
             .iter()
@@ -233,16 +693,18 @@ impl Session {
 
 impl Drop for Start {
     fn drop(&mut self) {
-        if !self.mountpoint.as_os_str().is_empty() {
-            let _ = unmount_sync(&self.mountpoint);
+        if self.unmount_on_drop && !self.mountpoint.as_os_str().is_empty() {
+            let _ = unmount_sync(&self.fusermount_cmd, &self.mountpoint);
         }
     }
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
-        if let Some(mountpoint) = self.mountpoint.get_mut().unwrap().take() {
-            let _ = unmount_sync(&mountpoint);
+        if self.unmount_on_drop {
+            if let Some(mountpoint) = self.mountpoint.get_mut().unwrap().take() {
+                let _ = unmount_sync(&self.fusermount_cmd, &mountpoint);
+            }
         }
 
         drop(DumbFd(*self.session_fd.get_ref())); // Close
@@ -257,6 +719,7 @@ impl<'o> Dispatch<'o> {
             Lookup(incoming) => incoming.common,
             Forget(incoming) => incoming.common,
             Getattr(incoming) => incoming.common,
+            Setattr(incoming) => incoming.common,
             Readlink(incoming) => incoming.common,
             Symlink(incoming) => incoming.common,
             Mknod(incoming) => incoming.common,
@@ -264,6 +727,7 @@ impl<'o> Dispatch<'o> {
             Unlink(incoming) => incoming.common,
             Rmdir(incoming) => incoming.common,
             Link(incoming) => incoming.common,
+            Rename(incoming) => incoming.common,
             Open(incoming) => incoming.common,
             Read(incoming) => incoming.common,
             Write(incoming) => incoming.common,
@@ -282,6 +746,9 @@ impl<'o> Dispatch<'o> {
             Access(incoming) => incoming.common,
             Create(incoming) => incoming.common,
             Bmap(incoming) => incoming.common,
+            Destroy(incoming) => incoming.common,
+            Ioctl(incoming) => incoming.common,
+            Poll(incoming) => incoming.common,
         };
 
         common.into_generic_op()
@@ -314,15 +781,71 @@ impl Endpoint<'_> {
             };
 
             match result {
-                // Interrupted
-                //TODO: libfuse docs say that this has some side effects
-                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
-
+                Err(error) if is_retried_read(&error) => continue,
                 result => break result,
             }
         };
 
-        let (header, opcode) = InHeader::from_bytes(&buffer[..bytes?])?;
+        self.dispatch(bytes?, dispatcher).await
+    }
+
+    // For event loops other than tokio's, which wait for raw_fd() to be readable by themselves
+    // and then call this. None if there turned out to be nothing to read. Handlers are still
+    // futures, but receiving doesn't rely on tokio's reactor (although starting a session does)
+    pub async fn try_receive<'o, F, Fut>(
+        &'o mut self,
+        dispatcher: F,
+    ) -> FuseResult<Option<ControlFlow<()>>>
+    where
+        F: FnOnce(Dispatch<'o>) -> Fut,
+        Fut: Future<Output = Done<'o>>,
+    {
+        let bytes = loop {
+            match read(self.session.raw_fd(), &mut self.local_buffer.0) {
+                Ok(bytes) => break bytes,
+                Err(Errno::EAGAIN) => return Ok(None),
+                Err(errno) if is_retried_read(&errno.into()) => continue,
+
+                // The connection is gone, as when receive() finds the device writable
+                Err(Errno::ENODEV) => {
+                    self.session.mountpoint.lock().unwrap().take();
+                    return Ok(Some(ControlFlow::Break(())));
+                }
+
+                Err(errno) => return Err(io::Error::from(errno).into()),
+            }
+        };
+
+        self.dispatch(bytes, dispatcher).await.map(Some)
+    }
+
+    async fn dispatch<'o, F, Fut>(
+        &'o mut self,
+        bytes: usize,
+        dispatcher: F,
+    ) -> FuseResult<ControlFlow<()>>
+    where
+        F: FnOnce(Dispatch<'o>) -> Fut,
+        Fut: Future<Output = Done<'o>>,
+    {
+        let buffer = &self.local_buffer.0;
+        let (header, opcode) = InHeader::from_bytes(&buffer[..bytes])?;
+        let body = &buffer[HEADER_END..header.len as usize];
+
+        match opcode {
+            proto::Opcode::NotifyReply => {
+                self.session.retrieved(&header, body);
+                return Ok(ControlFlow::Continue(()));
+            }
+
+            proto::Opcode::Interrupt => {
+                self.session.interrupt(&header, body);
+                return Ok(ControlFlow::Continue(()));
+            }
+
+            _ => (),
+        }
+
         let common = IncomingCommon {
             session: self.session,
             buffer: &mut self.local_buffer,
@@ -342,11 +865,10 @@ impl Endpoint<'_> {
             }
 
             match opcode {
-                Destroy => return Ok(ControlFlow::Break(())),
-
                 Lookup => dispatch!(Lookup),
                 Forget => dispatch!(Forget),
                 Getattr => dispatch!(Getattr),
+                Setattr => dispatch!(Setattr),
                 Readlink => dispatch!(Readlink),
                 Symlink => dispatch!(Symlink),
                 Mknod => dispatch!(Mknod),
@@ -354,6 +876,7 @@ impl Endpoint<'_> {
                 Unlink => dispatch!(Unlink),
                 Rmdir => dispatch!(Rmdir),
                 Link => dispatch!(Link),
+                Rename | Rename2 => dispatch!(Rename),
                 Open => dispatch!(Open),
                 Read => dispatch!(Read),
                 Write => dispatch!(Write),
@@ -374,6 +897,9 @@ impl Endpoint<'_> {
                 Bmap => dispatch!(Bmap),
                 BatchForget => dispatch!(Forget),
                 ReaddirPlus => dispatch!(Readdir),
+                Destroy => dispatch!(Destroy),
+                Ioctl => dispatch!(Ioctl),
+                Poll => dispatch!(Poll),
 
                 _ => {
                     log::warn!("Not implemented: {}", common.header);
@@ -386,16 +912,125 @@ impl Endpoint<'_> {
             }
         };
 
-        dispatcher(dispatch).await.consume();
-        Ok(ControlFlow::Continue(()))
+        let dispatched = dispatcher(dispatch);
+        if matches!(opcode, proto::Opcode::Forget | proto::Opcode::BatchForget) {
+            // Forgets get no reply, so there's nothing to fail and a panicking handler (such as
+            // one that trips on an inode it already dropped) must not take the session down.
+            // AssertUnwindSafe is a real caveat here: state that the handler was mutating (usually
+            // `&mut fs`) is left as it was at the panic, possibly half-updated, and is used as-is
+            // by later requests
+            tokio::pin!(dispatched);
+            let result = std::future::poll_fn(|cx| {
+                panic::catch_unwind(AssertUnwindSafe(|| dispatched.as_mut().poll(cx)))
+                    .map_or_else(|payload| Poll::Ready(Err(payload)), |poll| poll.map(Ok))
+            })
+            .await;
+
+            match result {
+                Ok(done) => done.consume(),
+                Err(_) => log::error!("Handler for forget req #{} panicked", header.unique),
+            }
+        } else {
+            dispatched.await.consume();
+        }
+
+        // Destroy is the last request ever sent by the kernel, after the dispatcher had its chance
+        // to flush and clean up
+        if matches!(opcode, proto::Opcode::Destroy) {
+            Ok(ControlFlow::Break(()))
+        } else {
+            Ok(ControlFlow::Continue(()))
+        }
+    }
+}
+
+impl StartOptions {
+    // Enforced by Owned::op(), requests handled inline are already serialized per endpoint
+    pub fn max_concurrent_data_ops(&mut self, max: usize) -> &mut Self {
+        assert!(max > 0, "at least one data operation must be allowed");
+
+        self.max_concurrent_data_ops = Some(max);
+        self
+    }
+
+    // Request buffers, max_write and max_pages (see ops::Init) all derive from this page count.
+    // It's clamped to what the kernel accepts, plus a page for request headers
+    pub fn max_pages(&mut self, pages: usize) -> &mut Self {
+        assert!(pages > 0, "max_pages must be positive");
+
+        self.buffer_pages = Some(pages.min(proto::MAX_MAX_PAGES + 1));
+        self
+    }
+
+    // Keeps inode numbers as the kernel sends them, even if Reply<Init>::root() is used. The root
+    // is then always Ino::ROOT (FUSE_ROOT_ID, 1), which the filesystem may not use for anything
+    // else. Meant for filesystems that manage inode numbers themselves, such as protocol bridges
+    pub fn preserve_root_ino(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_root_ino = preserve;
+        self
+    }
+
+    // Buffers are sized to fit the largest possible write request, see also max_pages()
+    pub fn max_write(&mut self, max_write: usize) -> &mut Self {
+        assert!(max_write > 0, "max_write must be positive");
+
+        let buffer_size = max_write + std::mem::size_of::<(InHeader, proto::WriteIn)>();
+        self.max_pages(buffer_size.div_ceil(page_size()))
     }
 }
 
 impl Start {
-    pub async fn start<F>(mut self, mut init: F) -> FuseResult<Arc<Session>>
+    pub async fn start<F>(self, init: F) -> FuseResult<Arc<Session>>
+    where
+        F: FnOnce(Op<'_, ops::Init>) -> Done<'_>,
+    {
+        self.start_with(&Default::default(), init).await
+    }
+
+    pub async fn start_with<F>(
+        mut self,
+        options: &StartOptions,
+        mut init: F,
+    ) -> FuseResult<Arc<Session>>
     where
         F: FnOnce(Op<'_, ops::Init>) -> Done<'_>,
     {
+        let mut session = self.new_session(options)?;
+        let mut init_buffer = session.buffers.get_mut().unwrap().pop().unwrap();
+
+        loop {
+            init = match session.handshake(&mut init_buffer, init).await? {
+                Handshake::Restart(init) => init,
+                Handshake::Done => {
+                    session.buffers.get_mut().unwrap().push(init_buffer);
+                    break Ok(Arc::new(session));
+                }
+            };
+        }
+    }
+
+    // See mount::open_cuse_sync()
+    pub async fn start_cuse(
+        mut self,
+        options: &StartOptions,
+        device: &CuseDevice<'_>,
+    ) -> FuseResult<Arc<Session>> {
+        let mut session = self.new_session(options)?;
+        let mut init_buffer = session.buffers.get_mut().unwrap().pop().unwrap();
+
+        session.cuse_handshake(&mut init_buffer, device).await?;
+        session.buffers.get_mut().unwrap().push(init_buffer);
+
+        Ok(Arc::new(session))
+    }
+
+    pub fn unmount_sync(mut self) -> Result<(), MountError> {
+        // This prevents Start::drop() from unmounting a second time
+        let mountpoint = std::mem::take(&mut self.mountpoint);
+        unmount_sync(&self.fusermount_cmd, &mountpoint)
+    }
+
+    fn new_session(&mut self, options: &StartOptions) -> FuseResult<Session> {
         let mountpoint = std::mem::take(&mut self.mountpoint);
         let session_fd = self.session_fd.take().into_raw_fd();
 
@@ -404,44 +1039,51 @@ impl Start {
 
         let (interrupt_tx, _) = broadcast::channel(INTERRUPT_BROADCAST_CAPACITY);
 
-        let buffer_pages = proto::MIN_READ_SIZE / page_size(); //TODO
+        let min_pages = proto::MIN_READ_SIZE.div_ceil(page_size());
+        let buffer_pages = options.buffer_pages.unwrap_or(min_pages).max(min_pages);
         let buffer_count = SHARED_BUFFERS; //TODO
         let buffers = std::iter::repeat_with(|| Buffer::new(buffer_pages))
             .take(buffer_count)
             .collect();
 
-        let mut session = Session {
+        Ok(Session {
             session_fd: AsyncFd::with_interest(session_fd, tokio::io::Interest::READABLE)?,
             interrupt_tx,
+            recent_interrupts: Mutex::new(VecDeque::with_capacity(INTERRUPT_BROADCAST_CAPACITY)),
             buffers: Mutex::new(buffers),
             buffer_semaphore: Arc::new(Semaphore::new(buffer_count)),
+            buffer_waiters: AtomicUsize::new(0),
+            buffer_exhaustions: AtomicU64::new(0),
             buffer_pages,
-            mountpoint: Mutex::new(Some(mountpoint)),
-        };
-
-        let mut init_buffer = session.buffers.get_mut().unwrap().pop().unwrap();
-
-        loop {
-            init = match session.handshake(&mut init_buffer, init).await? {
-                Handshake::Restart(init) => init,
-                Handshake::Done => {
-                    session.buffers.get_mut().unwrap().push(init_buffer);
-                    break Ok(Arc::new(session));
-                }
-            };
-        }
+            data_semaphore: options
+                .max_concurrent_data_ops
+                .map(|max| Arc::new(Semaphore::new(max))),
+            mountpoint: Mutex::new((!mountpoint.as_os_str().is_empty()).then_some(mountpoint)),
+            fusermount_cmd: self.fusermount_cmd.clone(),
+            unmount_on_drop: self.unmount_on_drop,
+            _fusermount_comm: self.fusermount_comm.take(),
+            splice_write: false,
+            init_flags: AtomicU32::new(0),
+            preserve_root_ino: options.preserve_root_ino,
+            root: AtomicU64::new(proto::ROOT_ID),
+            next_notify_unique: AtomicU64::new(1),
+            retrievals: Default::default(),
+        })
     }
 
-    pub fn unmount_sync(mut self) -> Result<(), MountError> {
-        // This prevents Start::drop() from unmounting a second time
-        let mountpoint = std::mem::take(&mut self.mountpoint);
-        unmount_sync(&mountpoint)
-    }
-
-    pub(crate) fn new(session_fd: DumbFd, mountpoint: PathBuf) -> Self {
+    pub(crate) fn new(
+        session_fd: DumbFd,
+        mountpoint: PathBuf,
+        fusermount_cmd: OsString,
+        unmount_on_drop: bool,
+        fusermount_comm: Option<UnixStream>,
+    ) -> Self {
         Start {
             session_fd,
             mountpoint,
+            fusermount_cmd,
+            unmount_on_drop,
+            fusermount_comm,
         }
     }
 }
@@ -463,10 +1105,21 @@ where
 
         let (buffer, permit) = {
             let semaphore = Arc::clone(&session.buffer_semaphore);
-            let permit = semaphore
-                .acquire_owned()
-                .await
-                .expect("Buffer semaphore error");
+            let permit = match Arc::clone(&semaphore).try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    session
+                        .buffer_exhaustions
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+
+                    // Decrements on drop, even if this future is cancelled while waiting
+                    let waiter = BufferWaiter::new(&session.buffer_waiters);
+                    let permit = semaphore.acquire_owned().await;
+                    drop(waiter);
+
+                    permit.expect("Buffer semaphore error")
+                }
+            };
 
             let mut buffers = session.buffers.lock().unwrap();
             let buffer = buffers.pop().expect("Buffer semaphore out of sync");
@@ -496,10 +1149,52 @@ where
         F: FnOnce(Op<'o, O>) -> Fut,
         Fut: Future<Output = Done<'o>>,
     {
-        match try_op(&self.session, &self.buffer.0, self.header) {
-            Ok(op) => handler(op).await.consume(),
-            Err(done) => done.consume(),
-        }
+        // Only data ops wait here, so that metadata requests are never starved by reads/writes
+        let opcode = proto::Opcode::try_from(self.header.opcode);
+        let _data_permit = match &self.session.data_semaphore {
+            Some(semaphore) if matches!(opcode, Ok(opcode) if is_data_op(opcode)) => {
+                Some(semaphore.acquire().await.expect("Data semaphore error"))
+            }
+
+            _ => None,
+        };
+
+        // Only the handler's future is held across the await, see spawn()
+        let handling = match try_op(&self.session, &self.buffer.0, self.header) {
+            Ok(op) => handler(op),
+            Err(done) => return done.consume(),
+        };
+
+        handling.await.consume();
+    }
+
+    // Handles the request in a new task, the buffer is released once the handler is done:
+    //
+    //     let (done, owned) = incoming.owned().await;
+    //     owned.spawn(|(request, reply)| Box::pin(async move { reply.ok() }));
+    //     done
+    pub fn spawn<F>(self, handler: F) -> JoinHandle<()>
+    where
+        O: Send + Sync + 'static,
+        F: for<'o> FnOnce(Op<'o, O>) -> Pin<Box<dyn Future<Output = Done<'o>> + Send + 'o>>,
+        F: Send + 'static,
+    {
+        tokio::spawn(async move { self.op(handler).await })
+    }
+}
+
+struct BufferWaiter<'a>(&'a AtomicUsize);
+
+impl<'a> BufferWaiter<'a> {
+    fn new(waiters: &'a AtomicUsize) -> Self {
+        waiters.fetch_add(1, atomic::Ordering::Relaxed);
+        BufferWaiter(waiters)
+    }
+}
+
+impl Drop for BufferWaiter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::Relaxed);
     }
 }
 
@@ -514,6 +1209,7 @@ impl<O> Drop for Owned<O> {
 
 const INTERRUPT_BROADCAST_CAPACITY: usize = 32;
 const SHARED_BUFFERS: usize = 32;
+const MAX_ERRNO: i32 = 511;
 const HEADER_END: usize = std::mem::size_of::<InHeader>();
 
 struct IncomingCommon<'o> {
@@ -529,29 +1225,181 @@ enum Handshake<F> {
 
 struct Buffer(Box<[u8]>);
 
+// Fails a request with EIO if its Reply goes away unanswered, such as when a handler panics or
+// its future is dropped halfway. The caller would otherwise hang until the connection is aborted
+pub(crate) struct ReplyGuard<'o> {
+    armed: Option<(&'o Session, u64)>,
+}
+
 impl<'o> IncomingCommon<'o> {
     fn into_generic_op(self) -> Op<'o> {
         let request = Request {
             header: self.header,
             body: (),
+            root: self.session.root(),
         };
 
         let reply = Reply {
             session: self.session,
             unique: self.header.unique,
             state: (),
+            guard: ReplyGuard::new(self.session, &self.header),
         };
 
         (request, reply)
     }
 }
 
+impl<'o> ReplyGuard<'o> {
+    fn new(session: &'o Session, header: &InHeader) -> Self {
+        // Forgets get no reply at all
+        let is_forget = [proto::Opcode::Forget, proto::Opcode::BatchForget]
+            .iter()
+            .any(|&opcode| header.opcode == opcode as u32);
+
+        ReplyGuard {
+            armed: (!is_forget).then_some((session, header.unique)),
+        }
+    }
+
+    pub(crate) fn disarm(&mut self) {
+        self.armed = None;
+    }
+}
+
+impl Drop for ReplyGuard<'_> {
+    fn drop(&mut self) {
+        if let Some((session, unique)) = self.armed.take() {
+            log::error!(
+                "Request {} was dropped without a reply, failing with EIO",
+                unique
+            );
+            if let Err(error) = session.fail(unique, Errno::EIO as i32) {
+                log::error!("Replying to request {}: {}", unique, error);
+            }
+        }
+    }
+}
+
 impl Buffer {
     fn new(pages: usize) -> Self {
         Buffer(vec![0; pages * page_size()].into_boxed_slice())
     }
 }
 
+// Entry point for fuzzing (see fuzz/), this must never panic regardless of input. Bodies go
+// through the same path as received requests, see try_op()
+#[doc(hidden)]
+pub fn parse_request(bytes: &[u8]) -> FuseResult<(InHeader, proto::Opcode)> {
+    let (header, opcode) = InHeader::from_bytes(bytes)?;
+    let body = &bytes[HEADER_END..];
+
+    macro_rules! parse {
+        ($op:ty) => {
+            parse_body::<$op>(bytes).map(drop)
+        };
+    }
+
+    use proto::Opcode::*;
+    match opcode {
+        // These are handled before dispatch, see Session::handshake() and Endpoint::receive()
+        Init => <&proto::InitIn>::toplevel_from(body, &header).map(drop),
+        CuseInit => <&proto::CuseInitIn>::toplevel_from(body, &header).map(drop),
+        NotifyReply => <(&proto::NotifyRetrieveIn, &[u8])>::toplevel_from(body, &header).map(drop),
+        Interrupt => <&proto::InterruptIn>::toplevel_from(body, &header).map(drop),
+
+        Lookup => parse!(ops::Lookup),
+        Forget | BatchForget => parse!(ops::Forget),
+        Getattr => parse!(ops::Getattr),
+        Setattr => parse!(ops::Setattr),
+        Readlink => parse!(ops::Readlink),
+        Symlink => parse!(ops::Symlink),
+        Mknod => parse!(ops::Mknod),
+        Mkdir => parse!(ops::Mkdir),
+        Unlink => parse!(ops::Unlink),
+        Rmdir => parse!(ops::Rmdir),
+        Link => parse!(ops::Link),
+        Rename | Rename2 => parse!(ops::Rename),
+        Open => parse!(ops::Open),
+        Read => parse!(ops::Read),
+        Write => parse!(ops::Write),
+        Statfs => parse!(ops::Statfs),
+        Release => parse!(ops::Release),
+        Fsync => parse!(ops::Fsync),
+        Setxattr => parse!(ops::Setxattr),
+        Getxattr => parse!(ops::Getxattr),
+        Listxattr => parse!(ops::Listxattr),
+        Removexattr => parse!(ops::Removexattr),
+        Flush => parse!(ops::Flush),
+        Opendir => parse!(ops::Opendir),
+        Readdir | ReaddirPlus => parse!(ops::Readdir),
+        Releasedir => parse!(ops::Releasedir),
+        Fsyncdir => parse!(ops::Fsyncdir),
+        Access => parse!(ops::Access),
+        Create => parse!(ops::Create),
+        Bmap => parse!(ops::Bmap),
+        Destroy => parse!(ops::Destroy),
+        Ioctl => parse!(ops::Ioctl),
+        Poll => parse!(ops::Poll),
+
+        _ => Ok(()),
+    }?;
+
+    Ok((header, opcode))
+}
+
+// Parses a whole request (header included) as an `O`, regardless of its actual opcode
+#[doc(hidden)]
+pub fn parse_body<'o, O: Operation<'o>>(bytes: &'o [u8]) -> FuseResult<Request<'o, O>>
+where
+    O::ReplyState: FromRequest<'o, O>,
+{
+    let (header, _) = InHeader::from_bytes(bytes)?;
+    parse_op(bytes, header, Ino::ROOT).map(|(request, _)| request)
+}
+
+fn splice_pipe(capacity: usize) -> Result<(DumbFd, DumbFd), Errno> {
+    let (rx, tx) = pipe2(OFlag::O_CLOEXEC)?;
+    let (rx, tx) = (DumbFd(rx), DumbFd(tx));
+
+    // Default pipe capacity (usually 16 pages) may fall short of large reads
+    let capacity = capacity.try_into().map_err(|_| Errno::EINVAL)?;
+    fcntl(tx.0, FcntlArg::F_SETPIPE_SZ(capacity))?;
+
+    Ok((rx, tx))
+}
+
+fn read_at(fd: RawFd, offset: u64, len: usize) -> Result<Vec<u8>, Errno> {
+    let mut offset: i64 = offset.try_into().map_err(|_| Errno::EINVAL)?;
+    let mut data = vec![0; len];
+    let mut filled = 0;
+
+    while filled < len {
+        match pread(fd, &mut data[filled..], offset)? {
+            0 => break,
+            read => {
+                filled += read;
+                offset += read as i64;
+            }
+        }
+    }
+
+    data.truncate(filled);
+    Ok(data)
+}
+
+// The device fails reads with ENOENT when the request that was about to be read got interrupted
+// or aborted in the meantime (for instance, its caller was killed). Nothing was consumed and there
+// is nothing to reply to, the read is simply retried. This has nothing to do with Interrupt
+// requests, which are actual messages handled by Session::interrupt()
+fn is_retried_read(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(Errno::ENOENT as i32)
+}
+
+fn is_data_op(opcode: proto::Opcode) -> bool {
+    matches!(opcode, proto::Opcode::Read | proto::Opcode::Write)
+}
+
 fn try_op<'o, O: Operation<'o>>(
     session: &'o Session,
     bytes: &'o [u8],
@@ -560,26 +1408,105 @@ fn try_op<'o, O: Operation<'o>>(
 where
     O::ReplyState: FromRequest<'o, O>,
 {
-    let body = match Structured::toplevel_from(&bytes[HEADER_END..header.len as usize], &header) {
-        Ok(body) => body,
+    match parse_op(bytes, header, session.root()) {
+        Ok((request, state)) => {
+            let reply = Reply {
+                session,
+                unique: header.unique,
+                state,
+                guard: ReplyGuard::new(session, &header),
+            };
+
+            Ok((request, reply))
+        }
+
         Err(error) => {
             log::error!("Parsing request {}: {:?}", header, error);
             let reply = Reply::<ops::Any> {
                 session,
                 unique: header.unique,
                 state: (),
+                guard: ReplyGuard::new(session, &header),
             };
 
-            return Err(reply.io_error());
+            Err(reply.io_error())
         }
-    };
+    }
+}
 
-    let request = Request { header, body };
-    let reply = Reply {
-        session,
-        unique: header.unique,
-        state: FromRequest::from_request(&request),
-    };
+fn parse_op<'o, O: Operation<'o>>(
+    bytes: &'o [u8],
+    header: InHeader,
+    root: Ino,
+) -> FuseResult<(Request<'o, O>, O::ReplyState)>
+where
+    O::ReplyState: FromRequest<'o, O>,
+{
+    let body = Structured::toplevel_from(&bytes[HEADER_END..header.len as usize], &header)?;
+    let request = Request { header, body, root };
+    let state = FromRequest::from_request(&request);
 
-    Ok((request, reply))
+    Ok((request, state))
+}
+
+// Each chunk is stored at the offset where the previous one ended
+fn store_chunks(
+    offset: u64,
+    data: &[u8],
+    max_chunk: usize,
+) -> Result<impl Iterator<Item = (u64, &[u8])>, Errno> {
+    let max_chunk = max_chunk.min(u32::MAX as usize);
+    if offset.checked_add(data.len() as u64).is_none() {
+        return Err(Errno::EFBIG);
+    }
+
+    let chunks = data.chunks(max_chunk).enumerate();
+    Ok(chunks.map(move |(i, chunk)| (offset + (i * max_chunk) as u64, chunk)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_enoent_is_retried() {
+        let error = io::Error::from(Errno::ENOENT);
+        assert!(is_retried_read(&error));
+    }
+
+    #[test]
+    fn other_device_errors_are_not_retried() {
+        for errno in [Errno::ENODEV, Errno::EIO, Errno::ECONNABORTED] {
+            assert!(!is_retried_read(&io::Error::from(errno)));
+        }
+    }
+
+    #[test]
+    fn store_chunks_are_contiguous() {
+        let data: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let chunks: Vec<_> = store_chunks(4096, &data, 4000).unwrap().collect();
+
+        let sizes: Vec<_> = chunks.iter().map(|(_, chunk)| chunk.len()).collect();
+        assert_eq!(sizes, [4000, 4000, 2000]);
+
+        let mut next = 4096;
+        for (offset, chunk) in &chunks {
+            assert_eq!(*offset, next);
+            assert_eq!(chunk, &&data[(offset - 4096) as usize..][..chunk.len()]);
+            next = offset + chunk.len() as u64;
+        }
+
+        assert_eq!(next, 4096 + data.len() as u64);
+    }
+
+    #[test]
+    fn store_chunks_edge_cases() {
+        assert_eq!(store_chunks(0, &[], 4000).unwrap().count(), 0);
+
+        let exact: Vec<_> = store_chunks(0, &[0; 8000], 4000).unwrap().collect();
+        assert_eq!(exact.len(), 2);
+        assert_eq!(exact[1].0, 4000);
+
+        assert!(store_chunks(u64::MAX, &[0], 4000).is_err());
+    }
 }